@@ -0,0 +1,128 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use socket2::{Domain, Protocol, Socket, Type};
+use crate::logging::Target;
+use crate::SERVER_PORT;
+
+#[derive(Debug)]
+pub enum AddressError {
+    Empty,
+    Malformed(String),
+    InvalidPort(String),
+    ResolutionFailed(String, io::Error),
+    NoAddresses(String),
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressError::Empty => write!(f, "No address provided"),
+            AddressError::Malformed(input) => write!(f, "Malformed address '{}'", input),
+            AddressError::InvalidPort(port) => write!(f, "Invalid port '{}'", port),
+            AddressError::ResolutionFailed(host, e) => write!(f, "Could not resolve '{}': {}", host, e),
+            AddressError::NoAddresses(host) => write!(f, "'{}' did not resolve to any address", host),
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+
+/// Splits user input into host and port. Accepts `host`, `host:port`, IPv4 literals,
+/// bare IPv6 literals and bracketed `[v6]` / `[v6]:port`. The port defaults to `SERVER_PORT`.
+pub fn split_host_port(input: &str) -> Result<(String, u16), AddressError> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err(AddressError::Empty);
+    }
+
+    if let Some(rest) = input.strip_prefix('[') {
+        let Some((host, after)) = rest.split_once(']') else {
+            return Err(AddressError::Malformed(input.to_string()));
+        };
+        let port = match after.strip_prefix(':') {
+            Some(port) => parse_port(port)?,
+            None if after.is_empty() => SERVER_PORT,
+            None => return Err(AddressError::InvalidPort(after.to_string())),
+        };
+        return Ok((host.to_string(), port));
+    }
+
+    if input.parse::<Ipv6Addr>().is_ok() {
+        return Ok((input.to_string(), SERVER_PORT));
+    }
+
+    match input.rsplit_once(':') {
+        Some((host, port)) => Ok((host.to_string(), parse_port(port)?)),
+        None => Ok((input.to_string(), SERVER_PORT)),
+    }
+}
+
+fn parse_port(port: &str) -> Result<u16, AddressError> {
+    port.parse::<u16>().map_err(|_| AddressError::InvalidPort(port.to_string()))
+}
+
+
+pub fn format_host_port(host: &str, port: u16) -> String {
+    if host.parse::<Ipv6Addr>().is_ok() {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+
+/// Resolves an address typed by the user, looking hostnames up through the system resolver.
+/// IPv4 results are preferred when a hostname has both kinds of record.
+pub fn resolve_server_address(input: &str) -> Result<SocketAddr, AddressError> {
+    if let Ok(address) = input.trim().parse::<SocketAddr>() {
+        return Ok(address);
+    }
+    let (host, port) = split_host_port(input)?;
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+
+    let addresses: Vec<SocketAddr> = (host.as_str(), port)
+        .to_socket_addrs()
+        .map_err(|e| AddressError::ResolutionFailed(host.clone(), e))?
+        .collect();
+
+    addresses
+        .iter()
+        .find(|address| address.is_ipv4())
+        .or_else(|| addresses.first())
+        .copied()
+        .ok_or(AddressError::NoAddresses(host))
+}
+
+
+/// Binds `[::]:port` with `IPV6_V6ONLY` turned off, so it accepts both IPv6 and IPv4 (as mapped
+/// addresses) whatever the OS default is. Falls back to `0.0.0.0:port` where IPv6 is unavailable.
+pub fn bind_dual_stack(port: u16) -> io::Result<UdpSocket> {
+    match bind_ipv6_any(port) {
+        Ok(socket) => Ok(socket),
+        Err(e) => {
+            log_warn!(Target::Net, "IPv6 bind failed ({}), falling back to IPv4", e);
+            UdpSocket::bind(("0.0.0.0", port))
+        }
+    }
+}
+
+fn bind_ipv6_any(port: u16) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port).into())?;
+    Ok(socket.into())
+}
+
+
+/// Binds a local socket of the same address family as `remote`.
+pub fn bind_for_remote(remote: &SocketAddr, port: u16) -> io::Result<UdpSocket> {
+    if remote.is_ipv6() {
+        UdpSocket::bind(("::", port))
+    } else {
+        UdpSocket::bind(("0.0.0.0", port))
+    }
+}
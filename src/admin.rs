@@ -0,0 +1,200 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use colored::Colorize;
+use crate::logging::Target;
+use crate::server::Server;
+
+const BAN_FILE: &str = "bans.json";
+
+pub const ADMIN_HELP: &str = "\
+Commands:
+  list                          show connected peers
+  kick <id> [reason]            disconnect a peer
+  ban <id|address> [duration]   ban an address, e.g. 30m, 2h, 1d (permanent if omitted)
+  unban <address>               lift a ban
+  bans                          show active bans
+  say <message>                 send a server message to every room
+  set <setting> <value>         max_players, max_spectators, max_rooms, join_in_progress
+  help                          show this list";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BanTarget {
+    Peer(i32),
+    Address(IpAddr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdminCommand {
+    List,
+    Kick { peer_id: i32, reason: Option<String> },
+    Ban { target: BanTarget, duration: Option<Duration> },
+    Unban(IpAddr),
+    Bans,
+    Say(String),
+    Set { setting: String, value: String },
+    Help,
+}
+
+
+/// Parses durations such as `45s`, `30m`, `2h` or `1d`.
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    let input = input.trim();
+    let split = input.find(|c: char| !c.is_ascii_digit()).unwrap_or(input.len());
+    let (amount, unit) = input.split_at(split);
+    let amount: u64 = amount.parse().map_err(|_| format!("Invalid duration '{}'", input))?;
+    let seconds = match unit {
+        "s" | "" => amount,
+        "m" => amount * 60,
+        "h" => amount * 60 * 60,
+        "d" => amount * 60 * 60 * 24,
+        _ => return Err(format!("Unknown duration unit '{}'", unit)),
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+
+pub fn parse_admin_command(line: &str) -> Result<AdminCommand, String> {
+    let line = line.trim();
+    let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let rest = rest.trim();
+    let mut args = rest.split_whitespace();
+
+    match command.to_lowercase().as_str() {
+        "list" | "ls" => Ok(AdminCommand::List),
+        "kick" => {
+            let peer_id = args
+                .next()
+                .ok_or("Usage: kick <id> [reason]")?
+                .parse::<i32>()
+                .map_err(|_| "Peer id must be a number".to_string())?;
+            let reason: Vec<&str> = args.collect();
+            let reason = (!reason.is_empty()).then(|| reason.join(" "));
+            Ok(AdminCommand::Kick { peer_id, reason })
+        },
+        "ban" => {
+            let target = args.next().ok_or("Usage: ban <id|address> [duration]")?;
+            let target = match target.parse::<i32>() {
+                Ok(peer_id) => BanTarget::Peer(peer_id),
+                Err(_) => BanTarget::Address(target.parse::<IpAddr>().map_err(|_| format!("'{}' is neither a peer id nor an address", target))?),
+            };
+            let duration = args.next().map(parse_duration).transpose()?;
+            Ok(AdminCommand::Ban { target, duration })
+        },
+        "unban" => {
+            let address = args.next().ok_or("Usage: unban <address>")?;
+            Ok(AdminCommand::Unban(address.parse::<IpAddr>().map_err(|_| format!("Invalid address '{}'", address))?))
+        },
+        "bans" => Ok(AdminCommand::Bans),
+        "say" => {
+            if rest.is_empty() {
+                return Err("Usage: say <message>".to_string());
+            }
+            Ok(AdminCommand::Say(rest.to_string()))
+        },
+        "set" => {
+            let (Some(setting), Some(value)) = (args.next(), args.next()) else {
+                return Err("Usage: set <setting> <value>".to_string());
+            };
+            Ok(AdminCommand::Set { setting: setting.to_lowercase(), value: value.to_string() })
+        },
+        "help" | "?" => Ok(AdminCommand::Help),
+        "" => Err(String::new()),
+        other => Err(format!("Unknown command '{}', type 'help' for a list", other)),
+    }
+}
+
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+/// Banned addresses with their expiry as unix seconds, `None` meaning permanent. Saved to `bans.json`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BanList {
+    entries: HashMap<IpAddr, Option<u64>>,
+}
+
+impl BanList {
+    pub fn load() -> Self {
+        let bans: BanList = std::fs::read_to_string(BAN_FILE)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default();
+        if !bans.entries.is_empty() {
+            log_info!(Target::Net, "Loaded {} bans from {}", bans.entries.len(), BAN_FILE);
+        }
+        bans
+    }
+
+    fn save(&self) {
+        match serde_json::to_string_pretty(self) {
+            Ok(json) => {
+                if let Err(e) = std::fs::write(BAN_FILE, json) {
+                    log_warn!(Target::Net, "Failed to store bans: {}", e);
+                }
+            }
+            Err(e) => log_warn!(Target::Net, "Failed to serialize bans: {}", e),
+        }
+    }
+
+    pub fn is_banned(&self, address: IpAddr) -> bool {
+        match self.entries.get(&address.to_canonical()) {
+            Some(Some(expires_at)) => *expires_at > unix_now(),
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    pub fn ban(&mut self, address: IpAddr, duration: Option<Duration>) {
+        let expires_at = duration.map(|duration| unix_now() + duration.as_secs());
+        self.entries.insert(address.to_canonical(), expires_at);
+        self.save();
+    }
+
+    pub fn unban(&mut self, address: IpAddr) -> bool {
+        let removed = self.entries.remove(&address.to_canonical()).is_some();
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    /// Active bans with the seconds left on each, `None` for permanent ones.
+    pub fn active(&self) -> Vec<(IpAddr, Option<u64>)> {
+        let now = unix_now();
+        let mut active: Vec<(IpAddr, Option<u64>)> = self.entries
+            .iter()
+            .filter(|(address, _)| self.is_banned(**address))
+            .map(|(address, expires_at)| (*address, expires_at.map(|expires_at| expires_at - now)))
+            .collect();
+        active.sort();
+        active
+    }
+}
+
+
+/// Reads admin commands from stdin on a background thread and runs them against the server.
+pub fn start_admin_console(server: Arc<Mutex<Server>>) {
+    thread::spawn(move || {
+        println!("{}", "Admin console ready, type 'help' for commands".bright_cyan());
+        let stdin = std::io::stdin();
+        for line in stdin.lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            match parse_admin_command(&line) {
+                Ok(command) => {
+                    let output = server.lock().unwrap().execute_admin_command(command);
+                    println!("{}", output);
+                }
+                Err(e) if e.is_empty() => {}
+                Err(e) => eprintln!("{}", e.red()),
+            }
+        }
+    });
+}
@@ -0,0 +1,81 @@
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+use crate::logging::{PacketDirection, Target};
+
+const CAPTURE_MAGIC: &[u8; 8] = b"RMPCAP01";
+
+/// One datagram as it crossed the socket, `elapsed_micros` counted from the start of the capture.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CaptureRecord {
+    pub elapsed_micros: u64,
+    pub direction: PacketDirection,
+    pub peer: SocketAddr,
+    pub bytes: Vec<u8>,
+}
+
+struct CaptureWriter {
+    file: File,
+    started: Instant,
+}
+
+static CAPTURE: Lazy<Mutex<Option<CaptureWriter>>> = Lazy::new(|| Mutex::new(None));
+
+
+/// Starts recording every datagram sent or received by this process into the file at `path`.
+pub fn start_capture(path: &str) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(CAPTURE_MAGIC)?;
+    *CAPTURE.lock().unwrap() = Some(CaptureWriter { file, started: Instant::now() });
+    log_info!(Target::Net, "Capturing network traffic to {}", path);
+    Ok(())
+}
+
+/// Appends a datagram to the capture, if one is running. Records are written straight to the
+/// file so a crash or `process::exit` does not lose the tail of the capture.
+pub fn record(direction: PacketDirection, peer: SocketAddr, bytes: &[u8]) {
+    let mut capture = CAPTURE.lock().unwrap();
+    let Some(writer) = capture.as_mut() else {
+        return;
+    };
+    let record = CaptureRecord {
+        elapsed_micros: writer.started.elapsed().as_micros() as u64,
+        direction,
+        peer,
+        bytes: bytes.to_vec(),
+    };
+    let written = bincode::serialize(&record)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        .and_then(|encoded| writer.file.write_all(&encoded));
+    if let Err(e) = written {
+        log_error!(Target::Net, "Stopping capture after write failure: {}", e);
+        *capture = None;
+    }
+}
+
+
+/// Reads a whole capture file written by `start_capture`.
+pub fn read_capture(path: &str) -> io::Result<Vec<CaptureRecord>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != CAPTURE_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a capture file"));
+    }
+
+    let mut records = Vec::new();
+    loop {
+        match bincode::deserialize_from::<_, CaptureRecord>(&mut reader) {
+            Ok(record) => records.push(record),
+            Err(e) => match *e {
+                bincode::ErrorKind::Io(ref io_error) if io_error.kind() == io::ErrorKind::UnexpectedEof => break,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            },
+        }
+    }
+    Ok(records)
+}
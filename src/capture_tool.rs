@@ -0,0 +1,194 @@
+use macroquad_platformer::World;
+use rust_mp::capture::{read_capture, CaptureRecord};
+use rust_mp::client::Client;
+use rust_mp::logging::PacketDirection;
+use rust_mp::message::{unpack_datagram, ObjectType};
+use rust_mp::names::display_name;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+const USAGE: &str = "\
+Usage:
+  capture_tool decode <capture> [--peer ADDRESS]
+      print every datagram as a timeline of messages
+  capture_tool replay <capture> [--peer ADDRESS] [--until SECONDS]
+      feed the received datagrams into a client and print the state it ends up in";
+
+
+struct ToolOptions {
+    command: String,
+    path: String,
+    peer: Option<SocketAddr>,
+    until: Option<f64>,
+}
+
+fn parse_options(args: &[String]) -> Result<ToolOptions, String> {
+    if args.len() < 3 {
+        return Err("Missing arguments".to_string());
+    }
+    let mut options = ToolOptions {
+        command: args[1].clone(),
+        path: args[2].clone(),
+        peer: None,
+        until: None,
+    };
+
+    let mut flags = args[3..].iter();
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--peer" => {
+                let value = flags.next().ok_or("--peer needs a value")?;
+                options.peer = Some(value.parse().map_err(|_| format!("Invalid peer address '{}'", value))?);
+            },
+            "--until" => {
+                let value = flags.next().ok_or("--until needs a value")?;
+                options.until = Some(value.parse().map_err(|_| format!("Invalid time '{}'", value))?);
+            },
+            other => return Err(format!("Unknown option '{}'", other)),
+        }
+    }
+    Ok(options)
+}
+
+
+fn seconds(record: &CaptureRecord) -> f64 {
+    record.elapsed_micros as f64 / 1_000_000.0
+}
+
+fn describe_fields(map: &HashMap<String, ObjectType>) -> String {
+    let mut keys: Vec<&String> = map.keys().filter(|key| key.as_str() != "goal").collect();
+    keys.sort();
+    keys.iter()
+        .map(|key| format!("{}={:?}", key, map[*key]))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn decode(records: &[CaptureRecord]) {
+    for record in records {
+        let arrow = match record.direction {
+            PacketDirection::Sent => "->",
+            PacketDirection::Received => "<-",
+        };
+        println!("{:>10.3}s {} {} {}B", seconds(record), arrow, record.peer, record.bytes.len());
+        match unpack_datagram(&record.bytes) {
+            Ok(messages) => {
+                for message in messages {
+                    let line = format!("    #{:<6} {:<24} {}", message.get_sequence(), message.goal(), describe_fields(message.get_message_map()));
+                    println!("{}", line.trim_end());
+                }
+            }
+            Err(e) => println!("    undecodable datagram: {}", e),
+        }
+    }
+}
+
+
+fn replay(records: &[CaptureRecord], peer: Option<SocketAddr>, until: Option<f64>) -> Result<(), String> {
+    let received: Vec<&CaptureRecord> = records
+        .iter()
+        .filter(|record| record.direction == PacketDirection::Received)
+        .filter(|record| peer.is_none_or(|peer| record.peer == peer))
+        .filter(|record| until.is_none_or(|until| seconds(record) <= until))
+        .collect();
+
+    let mut peers: Vec<SocketAddr> = received.iter().map(|record| record.peer).collect();
+    peers.sort();
+    peers.dedup();
+    let server_address = match peers.as_slice() {
+        [] => return Err("No received datagrams to replay".to_string()),
+        [address] => *address,
+        _ => {
+            let listed: Vec<String> = peers.iter().map(|peer| peer.to_string()).collect();
+            return Err(format!("Capture holds traffic from several peers, pick one with --peer: {}", listed.join(", ")));
+        }
+    };
+
+    let world = Arc::new(Mutex::new(World::new()));
+    let players = Arc::new(Mutex::new(HashMap::new()));
+    let mut client = Client::for_replay(server_address, world, Arc::clone(&players))
+        .map_err(|e| format!("Could not create replay client: {}", e))?;
+
+    let mut message_count = 0;
+    for record in received.iter() {
+        match client.replay_datagram(&record.bytes) {
+            Ok(count) => message_count += count,
+            Err(e) => eprintln!("Skipping undecodable datagram at {:.3}s: {}", seconds(record), e),
+        }
+    }
+
+    println!("Replayed {} messages in {} datagrams from {}", message_count, received.len(), server_address);
+    println!("Personal id: {}", client.get_personal_id());
+    println!("Room: {}", client.get_room().unwrap_or_else(|| "(default)".to_string()));
+    if let Some(reason) = client.get_rejection() {
+        println!("Rejected: {}", reason);
+    }
+    if let Some(reason) = client.get_kick_reason() {
+        println!("Kicked: {}", reason);
+    }
+
+    let names = client.get_names();
+    let lobby = client.get_lobby();
+    println!("Lobby: {}", if lobby.started { "match started" } else { "waiting" });
+    for (peer_id, entry) in lobby.entries.iter() {
+        println!("    {:<20} {:?} ready={}", display_name(&names, *peer_id), entry.character_type, entry.ready);
+    }
+
+    let players = players.lock().unwrap();
+    let mut object_ids: Vec<&i32> = players.keys().collect();
+    object_ids.sort();
+    println!("Players: {}", players.len());
+    for object_id in object_ids {
+        let wrapper = &players[object_id].wrapper;
+        println!(
+            "    #{:<4} {:<20} {:?} at ({:.1}, {:.1}) health={} state={:?}",
+            object_id,
+            display_name(&names, wrapper.owner_id),
+            wrapper.character_type,
+            wrapper.position_data.0,
+            wrapper.position_data.1,
+            wrapper.health,
+            wrapper.state,
+        );
+    }
+    Ok(())
+}
+
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let options = match parse_options(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    let records = match read_capture(&options.path) {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("Could not read capture '{}': {}", options.path, e);
+            std::process::exit(1);
+        }
+    };
+
+    let result = match options.command.as_str() {
+        "decode" => {
+            let selected: Vec<CaptureRecord> = records
+                .into_iter()
+                .filter(|record| options.peer.is_none_or(|peer| record.peer == peer))
+                .collect();
+            decode(&selected);
+            Ok(())
+        },
+        "replay" => replay(&records, options.peer, options.until),
+        other => Err(format!("Unknown command '{}'\n{}", other, USAGE)),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,145 @@
+use macroquad::prelude::*;
+use serde::{Serialize, Deserialize};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use crate::connection::HOST_ID;
+
+pub const MAX_CHAT_LENGTH: usize = 160;
+pub const CHAT_RATE_LIMIT: usize = 5;
+pub const CHAT_RATE_WINDOW: Duration = Duration::from_secs(5);
+/// Sender id used for notices generated by the server itself.
+pub const SYSTEM_SENDER: i32 = -1;
+
+const VISIBLE_MESSAGES: usize = 8;
+const LOG_CAPACITY: usize = 50;
+const MESSAGE_FADE: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChatMessage {
+    pub sender_id: i32,
+    pub text: String,
+}
+
+impl ChatMessage {
+    pub fn system(text: &str) -> Self {
+        ChatMessage { sender_id: SYSTEM_SENDER, text: text.to_string() }
+    }
+}
+
+
+/// Trims the text, strips control characters and cuts it to `MAX_CHAT_LENGTH` characters.
+/// Returns `None` if nothing is left to send.
+pub fn sanitize_chat(text: &str) -> Option<String> {
+    let cleaned: String = text
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_CHAT_LENGTH)
+        .collect();
+    if cleaned.is_empty() { None } else { Some(cleaned) }
+}
+
+
+/// Sliding window limiter, the server keeps one per peer.
+#[derive(Debug, Clone, Default)]
+pub struct ChatRateLimiter {
+    sent: VecDeque<Instant>,
+}
+
+impl ChatRateLimiter {
+    /// Records a message if the peer is still under the limit.
+    pub fn try_send(&mut self, now: Instant) -> bool {
+        while self.sent.front().is_some_and(|sent| now.duration_since(*sent) >= CHAT_RATE_WINDOW) {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= CHAT_RATE_LIMIT {
+            return false;
+        }
+        self.sent.push_back(now);
+        true
+    }
+}
+
+
+/// Message log and input line drawn over the game. Enter opens the input and sends, Escape cancels.
+#[derive(Default)]
+pub struct ChatBox {
+    log: VecDeque<(ChatMessage, Instant)>,
+    input: String,
+    open: bool,
+}
+
+impl ChatBox {
+    pub fn new() -> Self {
+        ChatBox::default()
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    pub fn push(&mut self, message: ChatMessage) {
+        self.log.push_back((message, Instant::now()));
+        while self.log.len() > LOG_CAPACITY {
+            self.log.pop_front();
+        }
+    }
+
+    /// Handles keyboard input for the frame and returns a line to send, if one was submitted.
+    pub fn update(&mut self) -> Option<String> {
+        if !self.open {
+            if is_key_pressed(KeyCode::Enter) || is_key_pressed(KeyCode::T) {
+                self.open = true;
+                self.input.clear();
+                // Drain the key that opened the box so it is not typed into it
+                while get_char_pressed().is_some() {}
+            }
+            return None;
+        }
+
+        if is_key_pressed(KeyCode::Escape) {
+            self.open = false;
+            return None;
+        }
+        if is_key_pressed(KeyCode::Backspace) {
+            self.input.pop();
+        }
+        while let Some(c) = get_char_pressed() {
+            if !c.is_control() && self.input.chars().count() < MAX_CHAT_LENGTH {
+                self.input.push(c);
+            }
+        }
+        if is_key_pressed(KeyCode::Enter) {
+            self.open = false;
+            return sanitize_chat(&self.input);
+        }
+        None
+    }
+
+    /// Draws in screen space, call after `set_default_camera`.
+    pub fn draw(&self, sender_label: impl Fn(i32) -> String) {
+        let line_height = 20.0;
+        let bottom = screen_height() - 40.0;
+        let visible: Vec<&(ChatMessage, Instant)> = self.log
+            .iter()
+            .rev()
+            .filter(|(_, received)| self.open || received.elapsed() < MESSAGE_FADE)
+            .take(VISIBLE_MESSAGES)
+            .collect();
+
+        for (index, (message, _)) in visible.iter().enumerate() {
+            let y = bottom - line_height * (index as f32 + 1.0);
+            let (line, color) = match message.sender_id {
+                SYSTEM_SENDER => (message.text.clone(), YELLOW),
+                HOST_ID => (format!("{} (host): {}", sender_label(message.sender_id), message.text), WHITE),
+                id => (format!("{}: {}", sender_label(id), message.text), WHITE),
+            };
+            draw_text(&line, 10.0, y, 20.0, color);
+        }
+
+        if self.open {
+            draw_rectangle(5.0, bottom - 4.0, screen_width() - 10.0, line_height + 4.0, Color::new(0.0, 0.0, 0.0, 0.6));
+            draw_text(format!("Say: {}_", self.input), 10.0, bottom + 12.0, 20.0, WHITE);
+        }
+    }
+}
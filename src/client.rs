@@ -1,18 +1,25 @@
-use crate::message::{Message, ObjectType};
-use crate::network_sync::NetworkSync;
+use crate::address::bind_for_remote;
+use crate::capture;
+use crate::chat::ChatMessage;
+use crate::lobby::LobbyState;
+use crate::names::NameMap;
+use crate::logging::{PacketDirection, Target};
+use crate::message::{pack_messages, trace_messages, unpack_datagram, Message, ObjectType, PeerInfo, RejectReason, MAX_DATAGRAM_SIZE};
+use crate::server::ServerConfig;
 use crate::player::{DataWrapper,Player};
-use crate::{CLIENT_PORT,RPC_FN_TABLE};
-use crate::rpc_funcs::{invoke_rpc,RuntimeArg, RuntimeParams};
+use crate::{CLIENT_PORT, RPC_IDS};
+use crate::replay;
+use crate::rpc_funcs::{complete_call, execute_rpc, RpcIdTable};
 use crate::PLAYER_SIZE_DATA;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::net::{UdpSocket, SocketAddr};
 use std::io::Result;
-use std::str::FromStr;
 use std::thread;
 use std::sync::{Arc, Mutex, mpsc::{self, Sender, Receiver}};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::io::ErrorKind;
-use colored::Colorize;
 use macroquad::math::vec2;
 use macroquad_platformer::World;
 
@@ -26,27 +33,78 @@ pub struct Client {
     tx: Option<Sender<HashMap<String, ObjectType>>>,
     new_player_id: Option<i32>,
     player_map_mutex: Arc<Mutex<HashMap<i32,Player>>>,
+    sequence: u32,
+    outgoing: Vec<Message>,
+    session_token: Option<u64>,
+    migrate_from: Option<i32>,
+    spectator: bool,
+    room: Option<String>,
+    known_peers: Vec<PeerInfo>,
+    server_config: ServerConfig,
+    last_server_contact: Instant,
+    running: Arc<AtomicBool>,
+    rejection: Option<RejectReason>,
+    lobby: LobbyState,
+    chat_inbox: Vec<ChatMessage>,
+    name: Option<String>,
+    names: NameMap,
+    kicked: Option<String>,
+    rpc_ids: RpcIdTable,
 }
 
+pub const HOST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How far, in pixels, a predicted player may stray from the server's position before it is snapped back.
+const RECONCILE_DISTANCE: f32 = 24.0;
+
+type MessageChannel = (Sender<HashMap<String, ObjectType>>, Receiver<HashMap<String, ObjectType>>);
+
 
 
 impl Client{
-    pub fn new(server_address_ip: String, world: Arc<Mutex<World>>, players:Arc<Mutex<HashMap<i32, Player>>>) -> Result<Client> {
-        let mut server_address = server_address_ip.clone();
-        server_address = server_address;
-        let socket = UdpSocket::bind(format!("0.0.0.0:{}",CLIENT_PORT))?;
-        println!("Client bound to: {:?}", socket.local_addr()?);
+    pub fn new(server_address: SocketAddr, world: Arc<Mutex<World>>, players:Arc<Mutex<HashMap<i32, Player>>>) -> Result<Client> {
+        let socket = bind_for_remote(&server_address, CLIENT_PORT)?;
+        log_info!(Target::Net, "Client bound to: {:?}", socket.local_addr()?);
+        Client::with_socket(socket, server_address, world, players)
+    }
+
+
+    /// Client for replaying a capture. It binds an ephemeral port so it can run next to a real
+    /// client, and is fed through `replay_datagram` instead of its receive thread.
+    pub fn for_replay(server_address: SocketAddr, world: Arc<Mutex<World>>, players:Arc<Mutex<HashMap<i32, Player>>>) -> Result<Client> {
+        let socket = bind_for_remote(&server_address, 0)?;
+        Client::with_socket(socket, server_address, world, players)
+    }
+
+
+    fn with_socket(socket: UdpSocket, server_address: SocketAddr, world: Arc<Mutex<World>>, players:Arc<Mutex<HashMap<i32, Player>>>) -> Result<Client> {
         socket.set_nonblocking(true)?;
-        
+
         Ok(Client{
-            server_address:SocketAddr::from_str(&server_address).unwrap(),
+            server_address,
             socket:Arc::new(Mutex::new(socket)),
             personal_id:0,
             synced_players: Arc::new(Mutex::new(HashMap::new())),
-            world: world,
+            world,
             tx: None,
             new_player_id: None,
             player_map_mutex: players,
+            sequence: 0,
+            outgoing: Vec::new(),
+            session_token: None,
+            migrate_from: None,
+            spectator: false,
+            room: None,
+            known_peers: Vec::new(),
+            server_config: ServerConfig::default(),
+            last_server_contact: Instant::now(),
+            running: Arc::new(AtomicBool::new(true)),
+            rejection: None,
+            lobby: LobbyState::default(),
+            chat_inbox: Vec::new(),
+            name: None,
+            names: NameMap::new(),
+            kicked: None,
+            rpc_ids: RPC_IDS.clone(),
         })
     }
 
@@ -59,128 +117,336 @@ impl Client{
     pub fn get_personal_id(&self) -> i32 {
         self.personal_id
     }
+
+
+    pub fn get_session_token(&self) -> Option<u64> {
+        self.session_token
+    }
+
+
+    pub fn set_session_token(&mut self, session_token: Option<u64>) {
+        self.session_token = session_token;
+    }
+
+
+    /// Marks this client as taking over its previous id on a server that replaced the old host.
+    pub fn set_migrate_from(&mut self, previous_id: Option<i32>) {
+        self.migrate_from = previous_id;
+    }
+
+
+    pub fn set_spectator(&mut self, spectator: bool) {
+        self.spectator = spectator;
+    }
+
+
+    pub fn is_spectator(&self) -> bool {
+        self.spectator
+    }
+
+
+    /// Room to ask for on connect; the server confirms the room it placed this client in.
+    pub fn set_room(&mut self, room: Option<String>) {
+        self.room = room;
+    }
+
+
+    pub fn get_room(&self) -> Option<String> {
+        self.room.clone()
+    }
+
+
+    pub fn set_name(&mut self, name: Option<String>) {
+        self.name = name;
+    }
+
+
+    /// Reason given by the server when it kicked this client.
+    pub fn get_kick_reason(&self) -> Option<String> {
+        self.kicked.clone()
+    }
+
+
+    pub fn get_names(&self) -> NameMap {
+        self.names.clone()
+    }
+
+
+    /// Set when the server refused the connection attempt.
+    pub fn get_rejection(&self) -> Option<RejectReason> {
+        self.rejection
+    }
+
+
+    pub fn get_lobby(&self) -> LobbyState {
+        self.lobby.clone()
+    }
+
+
+    /// Chat lines received since the last call.
+    pub fn take_chat_messages(&mut self) -> Vec<ChatMessage> {
+        std::mem::take(&mut self.chat_inbox)
+    }
+
+
+    pub fn build_sync_message(&self) -> HashMap<String, ObjectType> {
+        let mut connect_message = HashMap::new();
+        connect_message.insert(String::from("goal"), ObjectType::StringMsg(String::from("sync")));
+        if let Some(token) = self.session_token {
+            connect_message.insert(String::from("session_token"), ObjectType::SessionToken(token));
+        }
+        if self.spectator {
+            connect_message.insert(String::from("spectator"), ObjectType::Boolean(true));
+        }
+        if let Some(name) = &self.name {
+            connect_message.insert(String::from("name"), ObjectType::StringMsg(name.clone()));
+        }
+        if let Some(room) = &self.room {
+            connect_message.insert(String::from("room"), ObjectType::StringMsg(room.clone()));
+        }
+        if let Some(previous_id) = self.migrate_from {
+            connect_message.insert(String::from("migrate_from"), ObjectType::Integer(previous_id));
+        }
+        connect_message.insert(String::from("rpc_table"), ObjectType::RpcTable(RPC_IDS.names().to_vec()));
+        connect_message
+    }
+
+
+    /// Function ids in use with the server, its own table until the handshake says otherwise.
+    pub fn rpc_ids(&self) -> &RpcIdTable {
+        &self.rpc_ids
+    }
+
+
+    pub fn get_known_peers(&self) -> Vec<PeerInfo> {
+        self.known_peers.clone()
+    }
+
+
+    /// Settings of the server we are connected to, as of its last peer list.
+    pub fn get_server_config(&self) -> ServerConfig {
+        self.server_config
+    }
+
+
+    pub fn server_timed_out(&self) -> bool {
+        self.personal_id != 0 && self.last_server_contact.elapsed() >= HOST_TIMEOUT
+    }
+
+
+    /// Stops the receive thread, which releases its handle on the client and its socket.
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
     
 
-    #[allow(unused_mut)]
     fn process_message(&mut self,message_received: &Message) -> HashMap<String,ObjectType>{
 
         let mut response_map = HashMap::new();
         let received_map = message_received.get_message_map();
-        println!("{:?}",received_map);
+        log_trace!(Target::Net, "{:?}",received_map);
         if received_map.contains_key("goal"){
             match received_map.get("goal"){
                 Some(ObjectType::StringMsg(goal)) => {
-                    println!("Received message with goal: {}", goal.as_str().bold());
+                    log_debug!(Target::Net, "Received message with goal: {}", goal);
                     match goal.as_str() {
                         "confirm connect" => {
                             if let Some(ObjectType::Integer(new_id)) = received_map.get("id") {
                                 self.personal_id = *new_id;
                             }else{
-                                eprintln!("ID not a valid i32")
+                                log_warn!(Target::Net, "ID not a valid i32")
+                            }
+                            if let Some(ObjectType::SessionToken(token)) = received_map.get("session_token") {
+                                self.session_token = Some(*token);
+                            }
+                            if let Some(ObjectType::StringMsg(room)) = received_map.get("room") {
+                                self.room = Some(room.clone());
+                            }
+                            // Calls use the server's ids from here on
+                            if let Some(ObjectType::RpcTable(names)) = received_map.get("rpc_table") {
+                                self.rpc_ids = RpcIdTable::from_names(names.clone());
+                                RPC_IDS.check_against(&self.rpc_ids, "the server");
+                            }
+                        },
+                        "reject_connect" => {
+                            if let Some(ObjectType::Rejection(reason)) = received_map.get("reason") {
+                                log_error!(Target::Net, "Server rejected the connection: {}", reason);
+                                self.rejection = Some(*reason);
+                            } else {
+                                log_warn!(Target::Net, "Invalid rejection reason");
+                            }
+                        },
+                        "chat_message" => {
+                            if let Some(ObjectType::Chat(message)) = received_map.get("message") {
+                                self.chat_inbox.push(message.clone());
+                            } else {
+                                log_warn!(Target::Net, "Invalid chat message type");
+                            }
+                        },
+                        "kicked" => {
+                            let reason = match received_map.get("reason") {
+                                Some(ObjectType::StringMsg(reason)) => reason.clone(),
+                                _ => "Kicked from the server".to_string(),
+                            };
+                            log_error!(Target::Net, "Kicked from the server: {}", reason);
+                            self.kicked = Some(reason);
+                            self.stop();
+                        },
+                        "names" => {
+                            if let Some(ObjectType::Names(names)) = received_map.get("names") {
+                                self.names = names.clone();
+                            } else {
+                                log_warn!(Target::Net, "Invalid name list type");
+                            }
+                        },
+                        "lobby_state" => {
+                            if let Some(ObjectType::Lobby(lobby)) = received_map.get("lobby") {
+                                self.lobby = lobby.clone();
+                            } else {
+                                log_warn!(Target::Net, "Invalid lobby state type");
+                            }
+                        },
+                        "peer_list" => {
+                            if let Some(ObjectType::PeerList(peers)) = received_map.get("peers") {
+                                self.known_peers = peers.clone();
+                            } else {
+                                log_warn!(Target::Net, "Invalid peer list type");
+                            }
+                            if let Some(ObjectType::Config(config)) = received_map.get("config") {
+                                self.server_config = *config;
+                            }
+                        },
+                        "reconnect_required" => {
+                            log_warn!(Target::Net, "Server dropped this connection, resuming session");
+                            response_map = self.build_sync_message();
+                        },
+                        "remove_player" => {
+                            if let Some(ObjectType::Integer(object_id)) = received_map.get("object_id") {
+                                self.synced_players.lock().unwrap().remove(object_id);
+                                self.player_map_mutex.lock().unwrap().remove(object_id);
+                            } else {
+                                log_warn!(Target::Gameplay, "object_id for remove_player was incorrectly supplied");
+                            }
+                        },
+                        "ping" => {
+                            if let Some(ObjectType::Integer(ping_id)) = received_map.get("ping_id") {
+                                response_map.insert("goal".to_string(), ObjectType::StringMsg("pong".to_string()));
+                                response_map.insert("ping_id".to_string(), ObjectType::Integer(*ping_id));
                             }
                         },
                         "ret_sync_players" => {
                             if let Some(ObjectType::PlayerMap(players)) = received_map.get("players"){
-                                self.synced_players = Arc::new(Mutex::new(players.clone()));
+                                // Build the players here, before the server replays buffered RPCs that target them
+                                let mut wrapper_map = self.player_map_mutex.lock().unwrap();
+                                let mut world = self.world.lock().unwrap();
+                                for (object_id, wrapper) in players.iter() {
+                                    if !wrapper_map.contains_key(object_id) {
+                                        wrapper_map.insert(*object_id, Player::construct_from_wrapper(*wrapper, &mut world, &PLAYER_SIZE_DATA));
+                                    }
+                                }
+                                drop(world);
+                                drop(wrapper_map);
+                                self.synced_players.lock().unwrap().extend(players.iter().map(|(id, wrapper)| (*id, *wrapper)));
                             }else{
-                                eprintln!("Invalid player map return type");
+                                log_warn!(Target::Gameplay, "Invalid player map return type");
                             }
                         },
                         "ret_player_obj_id" => {
                             if let Some(ObjectType::Integer(new_player_id)) = received_map.get("id"){
                                 self.new_player_id = Some(*new_player_id);
                             }else{
-                                eprintln!("Invalid player id return type");
+                                log_warn!(Target::Gameplay, "Invalid player id return type");
                             }
                         },
                         "add_player" => {
                             if let Some(player_obj) = received_map.get("player") {
                                 match player_obj {
-                                    ObjectType::Player(pl) => {
-                                        let mut world = self.world.lock().unwrap();
-                                        let mut pl = Player::construct_from_wrapper(*pl, &mut world, &*PLAYER_SIZE_DATA);
-                                        drop(world);
-                                        let object_id = pl.get_object_id();
-                                        let owner_id = pl.get_owner();
-
-                                        if !self.synced_players.lock().unwrap().contains_key(&pl.get_object_id()){
-                                            pl.set_owner(owner_id);
-                                            self.synced_players.lock().unwrap().insert(object_id, pl.wrapper);
-                                            let mut wrapper_map = self.player_map_mutex.lock().unwrap();
-                                            wrapper_map.insert(object_id, pl);                                                    
+                                    ObjectType::Player(wrapper) => {
+                                        let object_id = wrapper.object_id;
+                                        // Only build players we do not have yet, each one adds a collider to the world
+                                        let mut wrapper_map = self.player_map_mutex.lock().unwrap();
+                                        if let Entry::Vacant(entry) = wrapper_map.entry(object_id) {
+                                            let mut world = self.world.lock().unwrap();
+                                            entry.insert(Player::construct_from_wrapper(*wrapper, &mut world, &PLAYER_SIZE_DATA));
                                         }
-                                        
+                                        drop(wrapper_map);
+                                        self.synced_players.lock().unwrap().entry(object_id).or_insert(*wrapper);
                                     },
                                     _ => {
-                                        eprintln!("player field is invalid type");
+                                        log_warn!(Target::Gameplay, "player field is invalid type");
                                     }
                                 }
                             } else {
-                                eprintln!("Missing 'player' field in received_map");
+                                log_warn!(Target::Gameplay, "Missing 'player' field in received_map");
                             }
                         },
                         "motion_update_broadcast" => {
                             if let Some(ObjectType::Integer(pl_id)) = received_map.get("object_id") {
                                 if let Some(pl) = self.player_map_mutex.lock().unwrap().get_mut(pl_id) {
                                     if let Some(ObjectType::MotionData(motion_data)) = received_map.get("motion_data"){
-                                        pl.wrapper.position_data = (motion_data.x, motion_data.y);
-                                        pl.wrapper.speed_data = (motion_data.x_speed, motion_data.y_speed);
-                                        pl.wrapper.state = motion_data.animation_state;
-                                        pl.wrapper.facing_right = motion_data.facing_right;
-
                                         let mut locked_world = self.world.lock().unwrap();
-                                        locked_world.set_actor_position(pl.collider, vec2(motion_data.x, motion_data.y));
-                                        
-                                        pl.speed = vec2(motion_data.x_speed, motion_data.y_speed);
-                                        pl.facing_right = motion_data.facing_right;
+                                        let server_position = vec2(motion_data.x, motion_data.y);
+                                        pl.health = motion_data.health;
+                                        pl.wrapper.health = motion_data.health;
+                                        pl.is_dead = motion_data.health <= 0;
+
+                                        // Our own players run ahead on local prediction, only pull them back when they drift too far
+                                        let predicted = pl.wrapper.owner_id == self.personal_id;
+                                        let drifted = locked_world.actor_pos(pl.collider).distance(server_position) > RECONCILE_DISTANCE;
+                                        if !predicted || drifted {
+                                            pl.wrapper.position_data = (motion_data.x, motion_data.y);
+                                            pl.wrapper.speed_data = (motion_data.x_speed, motion_data.y_speed);
+                                            pl.wrapper.state = motion_data.animation_state;
+                                            pl.wrapper.facing_right = motion_data.facing_right;
+                                            locked_world.set_actor_position(pl.collider, server_position);
+
+                                            pl.speed = vec2(motion_data.x_speed, motion_data.y_speed);
+                                            pl.facing_right = motion_data.facing_right;
+                                        }
                                     } else {
-                                        eprintln!("Motion Data for motion updated was not provided in the proper format");
+                                        log_warn!(Target::Gameplay, "Motion Data for motion updated was not provided in the proper format");
                                     }
                                 } else{
-                                    eprintln!("Object with id {} not found in client's player map", pl_id);
+                                    log_warn!(Target::Gameplay, "Object with id {} not found in client's player map", pl_id);
                                 }
                             }else{
-                                eprintln!("object_id for motion_update_broadcast was incorrectly supplied");
+                                log_warn!(Target::Gameplay, "object_id for motion_update_broadcast was incorrectly supplied");
                             }
                         },
                         "rpc_call" => {
                             if let Some(ObjectType::RpcCall(rpc_data)) = received_map.get("rpc_data"){
-                                if let Some(func) = RPC_FN_TABLE.get(rpc_data.function_name.as_str()) {
-                                    let mut runtime_args = Vec::new();
-                                    let mut rpc_data_mut = rpc_data.clone();
-
-
-                                    let mut player_map_locked = self.player_map_mutex.lock().unwrap();
-                                    match func.get_runtime_params() {
-                                        RuntimeParams::Player => {
-                                            if let Some(ObjectType::Integer(player_id)) = rpc_data_mut.params.get(0) {
-                                                if let Some(player) = player_map_locked.get_mut(player_id) {
-                                                    runtime_args.push(RuntimeArg::Player(player));
-                                                } else {
-                                                    eprintln!("Player with ID {} not found", player_id);
-                                                }
-                                                rpc_data_mut.params.remove(0);
-                                            } else {
-                                                eprintln!("Expected player_id as first parameter for Player-based function");
-                                            }
-                                        }
-                                        RuntimeParams::None => { }
+                                let mut call = rpc_data.clone();
+                                let result = match self.rpc_ids.decode(&mut call) {
+                                    Ok(()) => {
+                                        replay::record_rpc(&call);
+                                        execute_rpc(&call, &self.player_map_mutex, &self.world)
                                     }
-
-                                    invoke_rpc(&rpc_data_mut, &mut runtime_args);
+                                    Err(e) => {
+                                        log_warn!(Target::Rpc, "Dropping RPC from the server: {}", e);
+                                        Some(Err(e))
+                                    }
+                                };
+                                if let Some(response) = result.and_then(|result| call.response(result)) {
+                                    response_map.insert("goal".to_string(), ObjectType::StringMsg("rpc_response".to_string()));
+                                    response_map.insert("response".to_string(), ObjectType::RpcResult(response));
                                 }
                             }
                         },
+                        "rpc_response" => {
+                            if let Some(ObjectType::RpcResult(response)) = received_map.get("response") {
+                                complete_call(response.clone());
+                            }
+                        },
                         _ =>{
-                            println!("{}", "Unknown message type!".red());
+                            log_warn!(Target::Net, "Unknown message type!");
                         }
                     }
                 }
                 None =>{
-                    eprintln!("Goal field empty");
+                    log_warn!(Target::Net, "Goal field empty");
                 }
                 _ =>{
-                    eprintln!("Invalid goal type!")
+                    log_warn!(Target::Net, "Invalid goal type!")
                 }
             }
         }
@@ -189,49 +455,82 @@ impl Client{
 
     
     fn receive_message(&mut self) -> Result<()> {
-        let mut buffer = [0u8; 1024];
-        
-        let (size, _) = {
-            let socket = self.socket.lock().unwrap();
-            match socket.recv_from(&mut buffer) {
-                Ok(result) => {
-                    result
-                },
-                Err(e) => {
-                    if e.kind() != ErrorKind::WouldBlock {
-                        eprintln!("Error encountered while trying to receive message: {}", e);
+        let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let size = {
+                let socket = self.socket.lock().unwrap();
+                match socket.recv_from(&mut buffer) {
+                    Ok((size, _)) => {
+                        self.last_server_contact = Instant::now();
+                        size
+                    },
+                    Err(e) => {
+                        if e.kind() != ErrorKind::WouldBlock {
+                            log_error!(Target::Net, "Error encountered while trying to receive message: {}", e);
+                        }
+                        return Ok(());
                     }
-                    return Ok(());
                 }
-            }
-        };
-        match bincode::deserialize::<Message>(&buffer[..size]) {
-            Ok(decoded) => {
-                let response_map = self.process_message(&decoded);
-                
-                if !response_map.is_empty() {
-                    self.send_message(&response_map)?;
-                    println!("Sent response: {:?}", response_map);
+            };
+            capture::record(PacketDirection::Received, self.server_address, &buffer[..size]);
+            match unpack_datagram(&buffer[..size]) {
+                Ok(messages) => {
+                    trace_messages(PacketDirection::Received, self.server_address, &messages);
+                    for decoded in messages.iter() {
+                        let response_map = self.process_message(decoded);
+
+                        if !response_map.is_empty() {
+                            self.send_message(&response_map)?;
+                            log_trace!(Target::Net, "Queued response: {:?}", response_map);
+                        }
+                    }
+                }
+                Err(e) => {
+                    log_warn!(Target::Net, "Failed to decode message: {}", e);
                 }
-            }
-            Err(e) => {
-                println!("Failed to decode message: {}", e);
             }
         }
-        
-        Ok(())
     }
 
-    pub fn send_message(&self,message: &HashMap<String,ObjectType>) -> Result<()> {
-        if let Ok(message_struct) = Message::new(-1, message.clone()) {
-            let message_bytes = bincode::serialize(&message_struct).unwrap();
-            
-            self.socket.lock().unwrap().send_to(&message_bytes, self.server_address)?;
-            println!("Sent packet to {}", self.server_address);
+    /// Queues a message for the server; queued messages are sent together by `flush_outgoing`.
+    pub fn send_message(&mut self,message: &HashMap<String,ObjectType>) -> Result<()> {
+        if let Ok(mut message_struct) = Message::new(-1, message.clone()) {
+            self.sequence = self.sequence.wrapping_add(1);
+            message_struct.set_sequence(self.sequence);
+            self.outgoing.push(message_struct);
         }else{
-            eprintln!("Failed to create message: Message malformed");
+            log_error!(Target::Net, "Failed to create message: Message malformed");
+        }
+
+        Ok(())
+    }
+
+
+    /// Processes a captured datagram as if it had just arrived. Responses are dropped, since
+    /// the server they would go to is not there.
+    pub fn replay_datagram(&mut self, bytes: &[u8]) -> std::result::Result<usize, bincode::Error> {
+        let messages = unpack_datagram(bytes)?;
+        for decoded in messages.iter() {
+            self.process_message(decoded);
+        }
+        self.last_server_contact = Instant::now();
+        self.outgoing.clear();
+        Ok(messages.len())
+    }
+
+
+    pub fn flush_outgoing(&mut self) -> Result<()> {
+        if self.outgoing.is_empty() {
+            return Ok(());
+        }
+        trace_messages(PacketDirection::Sent, self.server_address, &self.outgoing);
+        let datagrams = pack_messages(std::mem::take(&mut self.outgoing));
+        let socket = self.socket.lock().unwrap();
+        for datagram in datagrams.iter() {
+            capture::record(PacketDirection::Sent, self.server_address, datagram);
+            socket.send_to(datagram, self.server_address)?;
         }
-        
         Ok(())
     }
 
@@ -239,10 +538,10 @@ impl Client{
     pub fn send_to_receive_thread(&self, msg: HashMap<String, ObjectType>) -> Result<()>{
         if let Some(tx) = &self.tx {
             if let Err(e) = tx.send(msg) {
-                eprintln!("Failed to send to receive thread: {:?}", e);
+                log_error!(Target::Net, "Failed to send to receive thread: {:?}", e);
             }
         } else {
-            eprintln!("{}","The pipe to receive thread is none!".bold().bright_red());
+            log_error!(Target::Net, "The pipe to receive thread is none!");
         }
         Ok(())
     }
@@ -260,28 +559,29 @@ impl Client{
 
     pub fn start(&mut self, self_mutex: Arc<Mutex<Self>>) {
         let mut_ref = Arc::clone(&self_mutex);
-        let (tx, rx): (
-            Sender<HashMap<String, ObjectType>>,
-            Receiver<HashMap<String, ObjectType>>,
-        ) = mpsc::channel();
+        let (tx, rx): MessageChannel = mpsc::channel();
+        let running = Arc::clone(&self.running);
 
         
         self.tx = Some(tx);
         
 
         let _receive_thread = thread::spawn(move || {
-            loop {
+            while running.load(Ordering::SeqCst) {
                 {
                     let mut locked = mut_ref.lock().unwrap();
                     if let Err(e) = locked.receive_message() {
-                        eprintln!("Failed to receive message: {:?}", e);
+                        log_error!(Target::Net, "Failed to receive message: {:?}", e);
                     }
             
                     while let Ok(msg) = rx.try_recv() {
                         if let Err(e) = locked.send_message(&msg) {
-                            eprintln!("Sending message failed: {:?}", e);
+                            log_error!(Target::Net, "Sending message failed: {:?}", e);
                         }
                     }
+                    if let Err(e) = locked.flush_outgoing() {
+                        log_error!(Target::Net, "Flushing outgoing messages failed: {:?}", e);
+                    }
                 }
                 thread::sleep(Duration::from_millis(8));
             }
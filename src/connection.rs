@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use crate::chat::ChatRateLimiter;
+use crate::message::{Message, PeerInfo};
+use crate::relevancy::ReplicationState;
+use crate::room::DEFAULT_ROOM;
+use crate::session::{generate_session_token, DetachedSession};
+
+/// The hosting player is not a peer of its own server; objects it owns use this id.
+pub const HOST_ID: i32 = 0;
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DISCONNECT_LINGER: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Disconnecting,
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerConnection {
+    pub address: SocketAddr,
+    pub session_token: u64,
+    pub is_spectator: bool,
+    pub room: String,
+    pub state: ConnectionState,
+    pub last_seen: Instant,
+    pub rtt: Option<Duration>,
+    pub local_sequence: u32,
+    pub remote_sequence: u32,
+    pub owned_objects: Vec<i32>,
+    pub outgoing: Vec<Message>,
+    pub replication: HashMap<i32, ReplicationState>,
+    pub chat_limiter: ChatRateLimiter,
+    ping_sent_at: Option<Instant>,
+    ping_id: i32,
+    state_changed_at: Instant,
+}
+
+impl PeerConnection {
+    pub fn new(address: SocketAddr, session_token: u64) -> Self {
+        let now = Instant::now();
+        PeerConnection {
+            address,
+            session_token,
+            is_spectator: false,
+            room: DEFAULT_ROOM.to_string(),
+            state: ConnectionState::Connecting,
+            last_seen: now,
+            rtt: None,
+            local_sequence: 0,
+            remote_sequence: 0,
+            owned_objects: Vec::new(),
+            outgoing: Vec::new(),
+            replication: HashMap::new(),
+            chat_limiter: ChatRateLimiter::default(),
+            ping_sent_at: None,
+            ping_id: 0,
+            state_changed_at: now,
+        }
+    }
+
+    pub fn set_state(&mut self, state: ConnectionState) {
+        if self.state != state {
+            self.state = state;
+            self.state_changed_at = Instant::now();
+        }
+    }
+
+    /// Called for every datagram received from the peer.
+    pub fn mark_received(&mut self, sequence: u32) {
+        self.last_seen = Instant::now();
+        if sequence > self.remote_sequence {
+            self.remote_sequence = sequence;
+        }
+        if self.state == ConnectionState::Disconnecting {
+            self.set_state(ConnectionState::Connected);
+        }
+    }
+
+    /// Returns the sequence number to stamp on the next outgoing message.
+    pub fn next_sequence(&mut self) -> u32 {
+        self.local_sequence = self.local_sequence.wrapping_add(1);
+        self.local_sequence
+    }
+
+    pub fn ping_due(&self) -> bool {
+        match self.ping_sent_at {
+            Some(sent_at) => sent_at.elapsed() >= PING_INTERVAL,
+            None => true,
+        }
+    }
+
+    /// Records an outgoing ping and returns its id.
+    pub fn start_ping(&mut self) -> i32 {
+        self.ping_id = self.ping_id.wrapping_add(1);
+        self.ping_sent_at = Some(Instant::now());
+        self.ping_id
+    }
+
+    pub fn complete_ping(&mut self, ping_id: i32) {
+        if ping_id == self.ping_id {
+            if let Some(sent_at) = self.ping_sent_at {
+                self.rtt = Some(sent_at.elapsed());
+            }
+        }
+    }
+
+    pub fn is_timed_out(&self) -> bool {
+        self.last_seen.elapsed() >= PEER_TIMEOUT
+    }
+
+    pub fn linger_expired(&self) -> bool {
+        self.state == ConnectionState::Disconnecting && self.state_changed_at.elapsed() >= DISCONNECT_LINGER
+    }
+}
+
+
+#[derive(Debug, Clone, Default)]
+pub struct BroadcastFilter {
+    include: Option<Vec<i32>>,
+    exclude: Vec<i32>,
+    room: Option<String>,
+}
+
+impl BroadcastFilter {
+    pub fn all() -> Self {
+        BroadcastFilter::default()
+    }
+
+    pub fn only(ids: &[i32]) -> Self {
+        BroadcastFilter { include: Some(ids.to_vec()), exclude: Vec::new(), room: None }
+    }
+
+    pub fn excluding(ids: &[i32]) -> Self {
+        BroadcastFilter { include: None, exclude: ids.to_vec(), room: None }
+    }
+
+    pub fn exclude(mut self, id: i32) -> Self {
+        self.exclude.push(id);
+        self
+    }
+
+    /// Restricts the filter to peers in the given room.
+    pub fn in_room(mut self, room: &str) -> Self {
+        self.room = Some(room.to_string());
+        self
+    }
+
+    pub fn allows_room(&self, room: &str) -> bool {
+        self.room.as_deref().is_none_or(|filter_room| filter_room == room)
+    }
+
+    pub fn allows(&self, id: i32) -> bool {
+        if self.exclude.contains(&id) {
+            return false;
+        }
+        match &self.include {
+            Some(include) => include.contains(&id),
+            None => true,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionTable {
+    peers: HashMap<i32, PeerConnection>,
+    detached: HashMap<u64, DetachedSession>,
+}
+
+impl ConnectionTable {
+    pub fn new() -> Self {
+        ConnectionTable::default()
+    }
+
+    fn gen_new_id(&self) -> i32 {
+        let mut key: i32 = 1;
+        while self.peers.contains_key(&key) || self.detached.values().any(|session| session.peer_id == key) {
+            key += 1;
+        }
+        key
+    }
+
+    pub fn register(&mut self, address: SocketAddr) -> i32 {
+        if let Some(id) = self.id_of(address) {
+            return id;
+        }
+        let new_id = self.gen_new_id();
+        self.peers.insert(new_id, PeerConnection::new(address, generate_session_token(new_id)));
+        new_id
+    }
+
+    /// Moves a peer out of the table, keeping its id and objects reserved for the grace period.
+    pub fn detach(&mut self, id: i32) -> Option<PeerConnection> {
+        let peer = self.peers.remove(&id)?;
+        self.detached.insert(peer.session_token, DetachedSession {
+            peer_id: id,
+            room: peer.room.clone(),
+            owned_objects: peer.owned_objects.clone(),
+            detached_at: Instant::now(),
+        });
+        Some(peer)
+    }
+
+    /// Gives a returning peer its old id back. Works both for detached sessions and for peers
+    /// that have not timed out yet but now talk from a new address.
+    pub fn resume(&mut self, session_token: u64, address: SocketAddr) -> Option<i32> {
+        if let Some(id) = self.peers.iter().find_map(|(id, peer)| (peer.session_token == session_token).then_some(*id)) {
+            if let Some(stale_id) = self.id_of(address).filter(|stale_id| *stale_id != id) {
+                self.peers.remove(&stale_id);
+            }
+            let peer = self.peers.get_mut(&id)?;
+            peer.address = address;
+            peer.replication.clear();
+            peer.set_state(ConnectionState::Connecting);
+            return Some(id);
+        }
+
+        let session = self.detached.remove(&session_token)?;
+        if let Some(stale_id) = self.id_of(address) {
+            self.peers.remove(&stale_id);
+        }
+        let mut peer = PeerConnection::new(address, session_token);
+        peer.room = session.room;
+        peer.owned_objects = session.owned_objects;
+        self.peers.insert(session.peer_id, peer);
+        Some(session.peer_id)
+    }
+
+    /// Holds an id and its objects for a peer expected to arrive later, such as a client
+    /// reconnecting after host migration.
+    pub fn reserve(&mut self, peer_id: i32, room: &str, owned_objects: Vec<i32>) {
+        self.detached.insert(generate_session_token(peer_id), DetachedSession {
+            peer_id,
+            room: room.to_string(),
+            owned_objects,
+            detached_at: Instant::now(),
+        });
+    }
+
+    pub fn resume_reserved(&mut self, peer_id: i32, address: SocketAddr) -> Option<i32> {
+        let token = self.detached
+            .iter()
+            .find_map(|(token, session)| (session.peer_id == peer_id).then_some(*token))?;
+        self.resume(token, address)
+    }
+
+    /// Public view of the connected peers with their addresses, used by clients to pick a new host.
+    pub fn peer_infos(&self) -> Vec<PeerInfo> {
+        let mut infos: Vec<PeerInfo> = self.peers
+            .iter()
+            .filter(|(_, peer)| peer.state != ConnectionState::Disconnecting)
+            .map(|(id, peer)| PeerInfo {
+                id: *id,
+                address: SocketAddr::new(peer.address.ip().to_canonical(), peer.address.port()),
+                spectator: peer.is_spectator,
+            })
+            .collect();
+        infos.sort_by_key(|info| info.id);
+        infos
+    }
+
+    /// Removes and returns sessions whose grace period has run out.
+    pub fn expire_sessions(&mut self) -> Vec<DetachedSession> {
+        let expired: Vec<u64> = self.detached
+            .iter()
+            .filter(|(_, session)| session.grace_expired())
+            .map(|(token, _)| *token)
+            .collect();
+        expired.into_iter().filter_map(|token| self.detached.remove(&token)).collect()
+    }
+
+    pub fn remove(&mut self, id: i32) -> Option<PeerConnection> {
+        self.peers.remove(&id)
+    }
+
+    pub fn get(&self, id: i32) -> Option<&PeerConnection> {
+        self.peers.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: i32) -> Option<&mut PeerConnection> {
+        self.peers.get_mut(&id)
+    }
+
+    pub fn id_of(&self, address: SocketAddr) -> Option<i32> {
+        self.peers
+            .iter()
+            .find_map(|(id, peer)| if peer.address == address { Some(*id) } else { None })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&i32, &PeerConnection)> {
+        self.peers.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&i32, &mut PeerConnection)> {
+        self.peers.iter_mut()
+    }
+
+    /// Player slots in use: connected non-spectators plus sessions held for reconnection.
+    pub fn player_count(&self) -> usize {
+        self.peers.values().filter(|peer| !peer.is_spectator).count() + self.detached.len()
+    }
+
+    pub fn spectator_count(&self) -> usize {
+        self.peers.values().filter(|peer| peer.is_spectator).count()
+    }
+
+    /// Peers and held sessions that belong to the room.
+    pub fn room_population(&self, room: &str) -> usize {
+        self.peers.values().filter(|peer| peer.room == room).count()
+            + self.detached.values().filter(|session| session.room == room).count()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Ids of every peer that is not disconnecting and passes the filter.
+    pub fn targets(&self, filter: &BroadcastFilter) -> Vec<i32> {
+        self.peers
+            .iter()
+            .filter(|(id, peer)| {
+                peer.state != ConnectionState::Disconnecting && filter.allows(**id) && filter.allows_room(&peer.room)
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+}
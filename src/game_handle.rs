@@ -1,17 +1,85 @@
 use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
+use std::fmt;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use crate::chat::{sanitize_chat, ChatMessage};
+use crate::admin::start_admin_console;
 use crate::client::Client;
-use crate::server::Server;
-use crate::player::{DataWrapper, Player};
+use crate::connection::HOST_ID;
+use crate::lobby::{LobbyEntry, LobbyState};
+use crate::logging::Target;
+use crate::names::NameMap;
+use crate::replay;
+use crate::room::{WorldFactory, DEFAULT_ROOM};
+use crate::server::{Server, ServerConfig};
+use crate::player::{DataWrapper, Player, PlayerInput};
 use crate::network_sync::NetworkSync;
-use crate::message::{MotionDataContainer, ObjectType, RpcCallContainer};
-use crate::PLAYER_SIZE_DATA;
+use crate::message::{ObjectType, PeerInfo, RejectReason, RpcCallContainer, RpcTarget};
+use crate::rpc_funcs::{authorize, complete_call, execute_rpc, invoke_rpc, register_call, take_queued_calls, RpcFuture, RuntimeArg};
+use serde::de::DeserializeOwned;
+use crate::session::{load_session_token, store_session_token};
+use crate::SERVER_PORT;
 use colored::*;
 use macroquad_platformer::World;
 
+const MIGRATION_DELAY: Duration = Duration::from_millis(500);
+const LOBBY_RESEND_INTERVAL: Duration = Duration::from_millis(250);
+
+
+#[derive(Debug)]
+pub enum ConnectError {
+    Rejected(RejectReason),
+    NoResponse(SocketAddr),
+    Socket(String),
+}
+
+impl ConnectError {
+    /// Exit code game_main uses when the server kicks this client mid-game.
+    pub const KICKED_EXIT_CODE: i32 = 5;
+
+    /// Process exit code used by game_main, so the launcher can tell the user why joining failed.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ConnectError::NoResponse(_) => 3,
+            ConnectError::Socket(_) => 4,
+            ConnectError::Rejected(reason) => 10 + reason.code(),
+        }
+    }
+
+    pub fn describe_exit_code(code: i32) -> Option<String> {
+        match code {
+            3 => Some("The server did not respond".to_string()),
+            4 => Some("Could not open a network socket".to_string()),
+            ConnectError::KICKED_EXIT_CODE => Some("You were kicked from the server".to_string()),
+            _ => RejectReason::from_code(code - 10).map(|reason| reason.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::Rejected(reason) => write!(f, "Connection rejected: {}", reason),
+            ConnectError::NoResponse(address) => write!(f, "Could not connect to the server at {} and receive an ID", address),
+            ConnectError::Socket(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+
+
+/// How a client presents itself when joining a server.
+#[derive(Debug, Clone, Default)]
+pub struct JoinOptions {
+    pub spectator: bool,
+    pub room: Option<String>,
+    pub name: Option<String>,
+}
 
 
 #[derive(Clone)]
@@ -20,11 +88,111 @@ pub struct GameHandle {
     server: Option<Arc<Mutex<Server>>>,
     player_wrapper_map: Arc<Mutex<HashMap<i32, Player>>>,
     personal_id: i32,
+    spectator: bool,
+    room: Option<String>,
+    name: Option<String>,
+    world_factory: Option<WorldFactory>,
+    last_lobby_send: Option<Instant>,
+    /// The migration in progress, with the channel its thread reports on.
+    migration: Option<(Migration, Arc<Mutex<Receiver<MigrationOutcome>>>)>,
+}
+
+/// State carried over from the lost host while looking for the new one.
+#[derive(Clone)]
+struct Migration {
+    previous_id: i32,
+    known_peers: Vec<PeerInfo>,
+    world: Arc<Mutex<World>>,
+    lobby: LobbyState,
+    names: NameMap,
+    config: ServerConfig,
+}
+
+enum MigrationOutcome {
+    /// Connected to the new host.
+    Joined(Arc<Mutex<Client>>, i32),
+    /// Our turn to take over, with the successors to try should that fail.
+    Promote(Vec<PeerInfo>),
+}
+
+
+/// Opens a client and handshakes with the server. Returns the client and the id the server gave us.
+fn connect_client(server_address: SocketAddr, world: Arc<Mutex<World>>, players: Arc<Mutex<HashMap<i32, Player>>>, join: &JoinOptions, migrate_from: Option<i32>) -> Result<(Arc<Mutex<Client>>, i32), ConnectError> {
+    let client = Client::new(server_address, world, players)
+        .map_err(|e| ConnectError::Socket(format!("Could not open a client socket for {}: {}", server_address, e)))?;
+    let client = Arc::new(Mutex::new(client));
+    {
+        client.lock().unwrap().start(Arc::clone(&client));
+    }
+    {
+        let mut client_lock = client.lock().unwrap();
+        client_lock.set_spectator(join.spectator);
+        client_lock.set_room(join.room.clone());
+        client_lock.set_name(join.name.clone());
+        match migrate_from {
+            Some(previous_id) => client_lock.set_migrate_from(Some(previous_id)),
+            None => client_lock.set_session_token(load_session_token(server_address)),
+        }
+    }
+    for _ in 1..6{
+        {
+            let client_lock = client.lock().unwrap();
+            let connect_message = client_lock.build_sync_message();
+
+            let _ = client_lock.send_to_receive_thread(connect_message);
+        }
+        thread::sleep(Duration::from_millis(200));
+        if let Some(reason) = client.lock().unwrap().get_rejection() {
+            client.lock().unwrap().stop();
+            return Err(ConnectError::Rejected(reason));
+        }
+        let new_id = client.lock().unwrap().get_personal_id();
+        if new_id!=0 {
+            if let Some(token) = client.lock().unwrap().get_session_token() {
+                store_session_token(server_address, token);
+            }
+            println!("{}", "\n═════════════════════════════".bold().bright_cyan());
+            println!("{}", "  Client is up and running!".bold().bright_green());
+            println!("{}", "═════════════════════════════".bold().bright_cyan());
+            return Ok((client, new_id));
+        }
+    }
+    client.lock().unwrap().stop();
+    Err(ConnectError::NoResponse(server_address))
+}
+
+
+fn request_synced_players(client_arc: &Arc<Mutex<Client>>) {
+    for _ in 1..6 {
+        {
+            let client_lock = client_arc.lock().unwrap();
+
+            let mut message = HashMap::new();
+            message.insert("goal".to_string(), ObjectType::StringMsg("get_sync_players".to_string()));
+
+            if let Err(e) = client_lock.send_to_receive_thread(message) {
+                log_error!(Target::Net, "Could not send message: {}", e);
+            }
+        }
+
+        thread::sleep(Duration::from_millis(200));
+
+        // The client builds the players itself when ret_sync_players arrives
+        let synced_players_mutex = client_arc.lock().unwrap().get_synced_players();
+        if !synced_players_mutex.lock().unwrap().is_empty() {
+            break;
+        }
+    }
 }
 
+
 impl GameHandle {
 
     pub fn add_player(&mut self, mut player: Player) -> Option<i32> {
+        if self.spectator {
+            log_warn!(Target::Gameplay, "Spectators cannot own players");
+            return None;
+        }
         if let Some(server_arc) = &self.server {
             let mut server_lock = server_arc.lock().unwrap();
     
@@ -50,7 +218,7 @@ impl GameHandle {
                     message.insert("player".to_string(), ObjectType::Player(player.wrapper));
 
                     if let Err(e) = client_lock.send_to_receive_thread(message) {
-                        eprintln!("Could not send message: {}", e);
+                        log_error!(Target::Net, "Could not send message: {}", e);
                     }
                 }
 
@@ -60,14 +228,13 @@ impl GameHandle {
                 let new_player_id = client_lock.get_new_player_id();
                 let synced_players = client_lock.get_synced_players();
                 drop(client_lock);
-                if new_player_id.is_some(){
-                    player.set_object_id(new_player_id.unwrap());
-                    synced_players.lock().unwrap().insert(new_player_id.unwrap(), player.wrapper);    
-
+                if let Some(new_player_id) = new_player_id {
+                    player.set_object_id(new_player_id);
                     player.set_owner(self.personal_id);
+                    synced_players.lock().unwrap().insert(new_player_id, player.wrapper);
 
                     let mut player_wrapper_map = self.player_wrapper_map.lock().unwrap();
-                    player_wrapper_map.insert(new_player_id.unwrap(), player);
+                    player_wrapper_map.insert(new_player_id, player);
                     return Some(player.get_object_id());
                 }
             }
@@ -79,130 +246,250 @@ impl GameHandle {
 
     pub fn request_synced_players(&mut self) {
         if let Some(client_arc) = &self.client {
-            for _ in 1..6 {
-                {
-                    let client_lock = client_arc.lock().unwrap();
+            request_synced_players(client_arc);
+        } else{
+            log_warn!(Target::Gameplay, "Cannot request players when running as the server, or client was not initialized correctly!");
+        }
+    }
 
-                    let mut message = HashMap::new();
-                    message.insert("goal".to_string(), ObjectType::StringMsg("get_sync_players".to_string()));
 
-                    if let Err(e) = client_lock.send_to_receive_thread(message) {
-                        eprintln!("Could not send message: {}", e);
-                    }
-                }
+    /// Hands the input for one of our players to the server, which simulates it on its next tick.
+    pub fn send_input(&self, object_id: i32, input: PlayerInput) {
+        if let Some(server_arc) = &self.server {
+            server_arc.lock().unwrap().set_input(object_id, &input);
+        } else if let Some(client_arc) = &self.client {
+            let client_locked = client_arc.lock().unwrap();
 
-                thread::sleep(Duration::from_millis(200));
+            let mut message: HashMap<String, ObjectType> = HashMap::new();
+            message.insert("goal".to_string(), ObjectType::StringMsg("player_input".to_string()));
+            message.insert("object_id".to_string(), ObjectType::Integer(object_id));
+            message.insert("input".to_string(), ObjectType::Input(input));
 
-                let client_lock = client_arc.lock().unwrap();
-                let synced_players_mutex = client_lock.get_synced_players();
-                let player_map = synced_players_mutex.lock().unwrap();
-                drop(client_lock);
-                if !player_map.is_empty() {
-                    let world = self.get_world();
-                    let mut world = world.lock().unwrap();
-                    for (_, wrapper) in player_map.iter(){
-                        let player = Player::construct_from_wrapper(*wrapper, &mut world, &*PLAYER_SIZE_DATA);
-                        let mut player_wrapper_map = self.player_wrapper_map.lock().unwrap();
-                        player_wrapper_map.insert(player.get_object_id(), player);
-                    }
-                    break;
-                }
+            if let Err(e) = client_locked.send_to_receive_thread(message) {
+                log_error!(Target::Net, "Failed to send message: {}", e);
             }
-            
-        } else{
-            eprintln!("Cannot request players when running as the server, or client was not initialized correctly!");
-        }
+        }else{ panic!("Game Handle has not been initialized properly"); }
     }
 
 
-    pub fn send_motion_update(&self, object_id: i32, motion_data: MotionDataContainer) {
-        if self.server.is_some(){
-            let server_locked = self.server.as_ref().unwrap().lock().unwrap();
+    /// Like `send_rpc`, but returns a future for the value the function returns. With several
+    /// targets the first response wins. Errors, such as an unknown function or arguments of the
+    /// wrong type, come back as the future's `Err`.
+    pub fn call_rpc<T: DeserializeOwned>(&self, mut call_container: RpcCallContainer, timeout: Duration) -> RpcFuture<T> {
+        let future = register_call(timeout);
+        call_container.call_id = Some(future.call_id());
+        self.send_rpc(call_container);
+        future
+    }
+
 
-            if let Some(wrapper) = server_locked.get_synced_players().lock().unwrap().get_mut(&object_id){
-                wrapper.position_data = (motion_data.x, motion_data.y);
-                wrapper.speed_data = (motion_data.x_speed, motion_data.y_speed);
-                let socket_map = server_locked.get_user_map();
-                let mut target_vector: Vec<&SocketAddr> = Vec::new();
-    
-                for (_, target) in socket_map.iter(){
-                    
-                    if target.to_string() != "127.0.0.1:13882" {
-                        target_vector.push(target);
-                    }
+    /// Sends the call to the peers its target names, running it here first when we are one of them.
+    pub fn send_rpc(&self, mut call_container: RpcCallContainer) {
+        replay::record_rpc(&call_container);
+        if let Some(server_arc) = &self.server {
+            server_arc.lock().unwrap().route_rpc(HOST_ID, DEFAULT_ROOM, call_container);
+        }else if let Some(client_arc) = &self.client {
+            let client_locked = client_arc.lock().unwrap();
+
+            let owner_id = call_container
+                .object_id
+                .and_then(|object_id| self.player_wrapper_map.lock().unwrap().get(&object_id).map(|player| player.wrapper.owner_id));
+            let owns_object = owner_id == Some(self.personal_id);
+            // The server would refuse it anyway, checking here keeps us from running our own copy
+            if let Err(e) = authorize(&call_container, self.personal_id, owner_id) {
+                log_warn!(Target::Rpc, "Not sending RPC: {}", e);
+                if let Some(response) = call_container.response(Err(e)) {
+                    complete_call(response);
                 }
-                server_locked.send_motion_update(target_vector, object_id, motion_data);
-            }else{
-                eprintln!("No object with ID {} found inside server's synced players", object_id);
+                return;
             }
-            
-        } else if self.client.is_some(){
-            let client_locked = self.client.as_ref().unwrap().lock().unwrap();
-            
-            if let Some(wrapper) = client_locked.get_synced_players().lock().unwrap().get_mut(&object_id){
-                wrapper.position_data = (motion_data.x, motion_data.y);
-                wrapper.speed_data = (motion_data.x_speed, motion_data.y_speed);
-                
-                let mut message: HashMap<String, ObjectType> = HashMap::new();
+            let (run_here, only_here) = match call_container.target {
+                RpcTarget::All => (true, false),
+                RpcTarget::Others | RpcTarget::Server => (false, false),
+                RpcTarget::Owner => (owns_object, owns_object),
+                RpcTarget::Peer(peer_id) => (peer_id == self.personal_id, peer_id == self.personal_id),
+            };
+            call_container.caller = self.personal_id;
+            if run_here {
+                let result = execute_rpc(&call_container, &self.player_wrapper_map, &client_locked.get_world());
+                if let Some(response) = result.and_then(|result| call_container.response(result)) {
+                    complete_call(response);
+                }
+            }
+            if only_here {
+                return;
+            }
+            if let Err(e) = client_locked.rpc_ids().encode(&mut call_container) {
+                log_error!(Target::Rpc, "Not sending RPC: {}", e);
+                if let Some(response) = call_container.response(Err(e)) {
+                    complete_call(response);
+                }
+                return;
+            }
+
+            let mut message: HashMap<String, ObjectType> = HashMap::new();
+
+            message.insert("goal".to_string(), ObjectType::StringMsg("rpc_call".to_string()));
+            message.insert("rpc_data".to_string(), ObjectType::RpcCall(call_container));
 
-                message.insert("goal".to_string(), ObjectType::StringMsg("object_pos_update".to_string()));
-                message.insert("object_id".to_string(), ObjectType::Integer(wrapper.object_id));
-                message.insert("motion_data".to_string(), ObjectType::MotionData(motion_data));
+            if let Err(e) = client_locked.send_to_receive_thread(message) {
+                log_error!(Target::Net, "Failed to send message: {}", e);
+            }
 
-                if let Err(e) = client_locked.send_to_receive_thread(message) {
-                    eprintln!("Failed to send message: {}", e);
+        }else{ panic!("Game Handle has not been initialized properly"); }
+    }
+
+
+    /// Runs the calls to functions that take the `GameHandle`. The network threads queue these
+    /// instead of running them, so call this once per frame from the game loop.
+    pub fn run_queued_rpcs(&self) {
+        for call in take_queued_calls() {
+            let result = invoke_rpc(&call, &mut [RuntimeArg::GameHandle(self)]);
+            let Some(response) = call.response(result) else {
+                continue;
+            };
+            if let Some(server_arc) = &self.server {
+                server_arc.lock().unwrap().deliver_rpc_response(response);
+            } else if response.caller == self.personal_id {
+                complete_call(response);
+            } else if let Some(client_arc) = &self.client {
+                let mut message: HashMap<String, ObjectType> = HashMap::new();
+                message.insert("goal".to_string(), ObjectType::StringMsg("rpc_response".to_string()));
+                message.insert("response".to_string(), ObjectType::RpcResult(response));
+                if let Err(e) = client_arc.lock().unwrap().send_to_receive_thread(message) {
+                    log_error!(Target::Net, "Failed to send message: {}", e);
                 }
-            }else{
-                eprintln!("No object with ID {} found inside client's synced players", object_id);
+            }
+        }
+    }
+
+
+    pub fn send_chat(&self, text: &str) {
+        let Some(text) = sanitize_chat(text) else {
+            return;
+        };
+        if let Some(server_arc) = &self.server {
+            server_arc.lock().unwrap().relay_chat(DEFAULT_ROOM, ChatMessage { sender_id: HOST_ID, text });
+        } else if let Some(client_arc) = &self.client {
+            let mut message: HashMap<String, ObjectType> = HashMap::new();
+            message.insert("goal".to_string(), ObjectType::StringMsg("chat".to_string()));
+            message.insert("text".to_string(), ObjectType::StringMsg(text));
+
+            if let Err(e) = client_arc.lock().unwrap().send_to_receive_thread(message) {
+                log_error!(Target::Net, "Failed to send message: {}", e);
             }
         }else{ panic!("Game Handle has not been initialized properly"); }
     }
 
 
-    pub fn send_rpc(&self, call_container: RpcCallContainer) {
-        if self.server.is_some(){
-            let server_locked = self.server.as_ref().unwrap().lock().unwrap();
+    pub fn take_chat_messages(&mut self) -> Vec<ChatMessage> {
+        if let Some(server_arc) = &self.server {
+            server_arc.lock().unwrap().take_host_chat()
+        }else if let Some(client_arc) = &self.client {
+            client_arc.lock().unwrap().take_chat_messages()
+        }else{ panic!("Game Handle has not been initialized properly"); }
+    }
 
-            let socket_map = server_locked.get_user_map();
-            let mut target_vector: Vec<&SocketAddr> = Vec::new();
 
-            for (_, target) in socket_map.iter(){
-                
-                if target.to_string() != "127.0.0.1:13882" {
-                    target_vector.push(target);
-                }
+    pub fn get_names(&self) -> NameMap {
+        if let Some(server_arc) = &self.server {
+            server_arc.lock().unwrap().get_names()
+        }else if let Some(client_arc) = &self.client {
+            client_arc.lock().unwrap().get_names()
+        }else{ panic!("Game Handle has not been initialized properly"); }
+    }
+
+
+    /// Sets the host's own name; clients send theirs with the connect handshake instead.
+    pub fn set_host_name(&mut self, name: &str) {
+        self.name = Some(name.to_string());
+        if let Some(server_arc) = &self.server {
+            server_arc.lock().unwrap().set_display_name(HOST_ID, name);
+        }
+    }
+
+
+    pub fn get_lobby(&self) -> LobbyState {
+        if let Some(server_arc) = &self.server {
+            server_arc.lock().unwrap().get_lobby()
+        }else if let Some(client_arc) = &self.client {
+            client_arc.lock().unwrap().get_lobby()
+        }else{ panic!("Game Handle has not been initialized properly"); }
+    }
+
+
+    /// Called every frame while in the lobby. Clients keep re-sending their choice until the
+    /// server's lobby state reflects it, so a lost packet only delays the update.
+    pub fn sync_lobby_choice(&mut self, entry: LobbyEntry) {
+        if self.spectator {
+            return;
+        }
+        if let Some(server_arc) = &self.server {
+            server_arc.lock().unwrap().update_lobby(DEFAULT_ROOM, HOST_ID, entry);
+        } else if let Some(client_arc) = &self.client {
+            let client_locked = client_arc.lock().unwrap();
+            if client_locked.get_lobby().get(self.personal_id) == Some(&entry) {
+                return;
+            }
+            if self.last_lobby_send.is_some_and(|sent| sent.elapsed() < LOBBY_RESEND_INTERVAL) {
+                return;
             }
-            server_locked.send_rpc(target_vector, call_container);
-        }else if self.client.is_some(){
-            let client_locked = self.client.as_ref().unwrap().lock().unwrap();
+            self.last_lobby_send = Some(Instant::now());
 
             let mut message: HashMap<String, ObjectType> = HashMap::new();
-
-            message.insert("goal".to_string(), ObjectType::StringMsg("rpc_call".to_string()));
-            message.insert("rpc_data".to_string(), ObjectType::RpcCall(call_container));
+            message.insert("goal".to_string(), ObjectType::StringMsg("lobby_update".to_string()));
+            message.insert("character".to_string(), ObjectType::Character(entry.character_type));
+            message.insert("ready".to_string(), ObjectType::Boolean(entry.ready));
 
             if let Err(e) = client_locked.send_to_receive_thread(message) {
-                eprintln!("Failed to send message: {}", e);
+                log_error!(Target::Net, "Failed to send message: {}", e);
             }
-
         }else{ panic!("Game Handle has not been initialized properly"); }
     }
 
 
+    /// Starts the match if this peer is the host and everyone in the lobby is ready.
+    pub fn start_match(&mut self) -> bool {
+        match &self.server {
+            Some(server_arc) => server_arc.lock().unwrap().start_match(),
+            None => false,
+        }
+    }
+
+
+    /// Lets the server open new rooms, and lets a client that gets promoted during migration do the same.
+    pub fn set_world_factory(&mut self, world_factory: WorldFactory) {
+        if let Some(server_arc) = &self.server {
+            server_arc.lock().unwrap().set_world_factory(Some(Arc::clone(&world_factory)));
+        }
+        self.world_factory = Some(world_factory);
+    }
+
+
+    pub fn kick_reason(&self) -> Option<String> {
+        self.client.as_ref().and_then(|client_arc| client_arc.lock().unwrap().get_kick_reason())
+    }
+
+
+    pub fn is_host(&self) -> bool {
+        self.server.is_some()
+    }
+
+
     pub fn get_world(&self) -> Arc<Mutex<World>> {
-        if self.server.is_some(){
-            self.server.as_ref().unwrap().lock().unwrap().get_world()
-        }else if self.client.is_some(){
-            self.client.as_ref().unwrap().lock().unwrap().get_world()
+        if let Some(server_arc) = &self.server {
+            server_arc.lock().unwrap().get_world()
+        }else if let Some(client_arc) = &self.client {
+            client_arc.lock().unwrap().get_world()
         }else{ panic!("Game Handle has not been initialized properly"); }
     }
 
 
     pub fn get_network_wrappers(&self) -> Arc<Mutex<HashMap<i32, DataWrapper>>> {
-        if self.server.is_some(){
-            self.server.as_ref().unwrap().lock().unwrap().get_synced_players()
-        }else if self.client.is_some(){
-            self.client.as_ref().unwrap().lock().unwrap().get_synced_players()
+        if let Some(server_arc) = &self.server {
+            server_arc.lock().unwrap().get_synced_players()
+        }else if let Some(client_arc) = &self.client {
+            client_arc.lock().unwrap().get_synced_players()
         }else{ panic!("Game Handle has not been initialized properly"); }
     }
 
@@ -217,9 +504,27 @@ impl GameHandle {
     }
 
 
-    fn launch_server(&mut self, world: Arc<Mutex<World>>) -> Result<(), std::io::Error> {
-        let server = Arc::new(Mutex::new(Server::new(world, Arc::clone(&self.player_wrapper_map))?));
+    pub fn is_spectator(&self) -> bool {
+        self.spectator
+    }
+
+
+    /// True when a player owned by this peer already exists, e.g. after resuming a session.
+    pub fn has_owned_player(&self) -> bool {
+        self.player_wrapper_map
+            .lock()
+            .unwrap()
+            .values()
+            .any(|player| player.get_owner() == self.personal_id)
+    }
+
+
+    fn launch_server(&mut self, world: Arc<Mutex<World>>, config: ServerConfig) -> Result<(), std::io::Error> {
+        let mut server = Server::new(world, Arc::clone(&self.player_wrapper_map), config)?;
+        server.set_world_factory(self.world_factory.clone());
+        let server = Arc::new(Mutex::new(server));
         server.lock().unwrap().start(Arc::clone(&server));
+        start_admin_console(Arc::clone(&server));
         self.server = Some(server);
         
         println!("{}", "\n═════════════════════════════".bold().bright_cyan());
@@ -230,65 +535,183 @@ impl GameHandle {
     }
 
 
-    fn launch_client(&mut self, server_ip: String, world: Arc<Mutex<World>>) -> Result<(), String> {
-        if server_ip.is_empty() {
-            return Err("No IP address provided".to_string());
+    /// Called every frame. When the host stops answering, the connected peer with the lowest id
+    /// promotes itself to server using its last known player state, and the others reconnect to it.
+    /// The search for the new host runs on a background thread, the old client stays in place
+    /// until it is over so the game keeps drawing.
+    pub fn check_host_migration(&mut self) {
+        if let Some((_, outcome)) = &self.migration {
+            let outcome = match outcome.lock().unwrap().try_recv() {
+                Ok(outcome) => outcome,
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => MigrationOutcome::Promote(Vec::new()),
+            };
+            if let Some((migration, _)) = self.migration.take() {
+                self.finish_migration(migration, outcome);
+            }
+            return;
         }
-        
-        let client = Arc::new(Mutex::new(Client::new(server_ip, world,Arc::clone(&self.player_wrapper_map)).unwrap()));
-        {
-            client.lock().unwrap().start(Arc::clone(&client));
+        let Some(client_arc) = &self.client else {
+            return;
+        };
+        let (timed_out, known_peers, world, lobby, names, config) = {
+            let client_lock = client_arc.lock().unwrap();
+            (
+                client_lock.server_timed_out(),
+                client_lock.get_known_peers(),
+                client_lock.get_world(),
+                client_lock.get_lobby(),
+                client_lock.get_names(),
+                client_lock.get_server_config(),
+            )
+        };
+        if !timed_out {
+            return;
         }
-        for _ in 1..6{
-            {
-                
-                let mut connect_message = HashMap::new();
-                connect_message.insert(String::from("goal"), ObjectType::StringMsg(String::from("sync")));
-
-                let _ = client.lock().unwrap().send_to_receive_thread(connect_message);
-                                
+
+        log_warn!(Target::Net, "Host stopped responding, starting host migration");
+        let previous_id = self.personal_id;
+        self.player_wrapper_map.lock().unwrap().retain(|_, player| player.get_owner() != HOST_ID);
+
+        let mut successors = known_peers.clone();
+        successors.sort_by_key(|peer| (peer.spectator, peer.id));
+        self.start_migration(Migration { previous_id, known_peers, world, lobby, names, config }, successors);
+    }
+
+
+    /// Tries the successors in order on a background thread, until one answers as the new host
+    /// or it is our turn to take over.
+    fn start_migration(&mut self, migration: Migration, successors: Vec<PeerInfo>) {
+        let (sender, receiver) = mpsc::channel();
+        let previous_id = migration.previous_id;
+        let world = Arc::clone(&migration.world);
+        let players = Arc::clone(&self.player_wrapper_map);
+        let join = JoinOptions { spectator: self.spectator, room: self.room.clone(), name: self.name.clone() };
+
+        thread::spawn(move || {
+            for (index, successor) in successors.iter().enumerate() {
+                if successor.id == previous_id {
+                    let _ = sender.send(MigrationOutcome::Promote(successors[index + 1..].to_vec()));
+                    return;
+                }
+                thread::sleep(MIGRATION_DELAY);
+                let address = SocketAddr::new(successor.address.ip(), SERVER_PORT);
+                log_info!(Target::Net, "Reconnecting to new host {} at {}", successor.id, address);
+                match connect_client(address, Arc::clone(&world), Arc::clone(&players), &join, Some(previous_id)) {
+                    Ok((client, personal_id)) => {
+                        request_synced_players(&client);
+                        let _ = sender.send(MigrationOutcome::Joined(client, personal_id));
+                        return;
+                    }
+                    Err(e) => log_warn!(Target::Net, "Could not reach peer {} as new host: {}", successor.id, e),
+                }
+            }
+            let _ = sender.send(MigrationOutcome::Promote(Vec::new()));
+        });
+        self.migration = Some((migration, Arc::new(Mutex::new(receiver))));
+    }
+
+
+    /// Swaps in the client or server the migration thread settled on.
+    /// The old client is only shut down once its replacement is in place, if promoting fails it
+    /// stays so the handle always has a client or a server to answer from.
+    fn finish_migration(&mut self, migration: Migration, outcome: MigrationOutcome) {
+        match outcome {
+            MigrationOutcome::Joined(client, personal_id) => {
+                self.shutdown_client();
+                self.personal_id = personal_id;
+                self.client = Some(client);
             }
-            thread::sleep(Duration::from_millis(200));
-            let new_id = client.lock().unwrap().get_personal_id();
-            if new_id!=0 {
-                self.personal_id = new_id;
-                println!("{}", "\n═════════════════════════════".bold().bright_cyan());
-                println!("{}", "  Client is up and running!".bold().bright_green());
-                println!("{}", "═════════════════════════════".bold().bright_cyan());
-                break;
+            MigrationOutcome::Promote(remaining) => {
+                let promoted = self.promote_to_server(
+                    Arc::clone(&migration.world),
+                    migration.config,
+                    migration.previous_id,
+                    &migration.known_peers,
+                    migration.lobby.clone(),
+                    migration.names.clone(),
+                );
+                match promoted {
+                    Ok(()) => self.shutdown_client(),
+                    Err(e) if remaining.is_empty() => log_error!(Target::Net, "Host migration failed: {}", e),
+                    Err(e) => {
+                        log_error!(Target::Net, "Could not take over as host: {}", e);
+                        self.start_migration(migration, remaining);
+                    }
+                }
             }
         }
-        if client.lock().unwrap().get_personal_id() == 0{
-            panic!("Could not connect to the server and receive an ID");
+    }
+
+
+    fn promote_to_server(&mut self, world: Arc<Mutex<World>>, config: ServerConfig, previous_id: i32, known_peers: &[PeerInfo], lobby: LobbyState, names: NameMap) -> Result<(), std::io::Error> {
+        self.launch_server(world, config)?;
+        self.room = None;
+        if let Some(server_arc) = &self.server {
+            server_arc.lock().unwrap().adopt_migrated_state(previous_id, known_peers, lobby, names);
+        }
+        self.personal_id = HOST_ID;
+        Ok(())
+    }
+
+
+    fn shutdown_client(&mut self) {
+        if let Some(client_arc) = self.client.take() {
+            client_arc.lock().unwrap().stop();
+            for _ in 0..50 {
+                if Arc::strong_count(&client_arc) == 1 {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
         }
+    }
+
+
+    fn launch_client(&mut self, server_address: SocketAddr, world: Arc<Mutex<World>>, migrate_from: Option<i32>) -> Result<(), ConnectError> {
+        let join = JoinOptions { spectator: self.spectator, room: self.room.clone(), name: self.name.clone() };
+        let (client, personal_id) = connect_client(server_address, world, Arc::clone(&self.player_wrapper_map), &join, migrate_from)?;
+        self.personal_id = personal_id;
         self.client = Some(client);
         self.request_synced_players();
         Ok(())
     }
 
     
-    pub fn construct_client(server_ip: String, world: Arc<Mutex<World>>) -> Arc<Mutex<Self>> {
+    pub fn construct_client(server_address: SocketAddr, world: Arc<Mutex<World>>, join: JoinOptions) -> Result<Arc<Mutex<Self>>, ConnectError> {
         let handle = GameHandle {
             client: None,
             server: None,
             player_wrapper_map: Arc::new(Mutex::new(HashMap::new())),
             personal_id: 0,
+            spectator: join.spectator,
+            room: join.room,
+            name: join.name,
+            world_factory: None,
+            last_lobby_send: None,
+            migration: None,
         };
         let handle_mutex = Arc::new(Mutex::new(handle));
-        handle_mutex.lock().unwrap().launch_client(server_ip,world).expect("Failed to launch client");
-        handle_mutex
+        handle_mutex.lock().unwrap().launch_client(server_address, world, None)?;
+        Ok(handle_mutex)
     }
 
 
-    pub fn construct_server(world: Arc<Mutex<World>>) -> Arc<Mutex<Self>> {
+    pub fn construct_server(world: Arc<Mutex<World>>, config: ServerConfig) -> Arc<Mutex<Self>> {
         let handle = GameHandle {
             client: None,
             server: None,
             player_wrapper_map: Arc::new(Mutex::new(HashMap::new())),
             personal_id: 0,
+            spectator: false,
+            room: None,
+            name: None,
+            world_factory: None,
+            last_lobby_send: None,
+            migration: None,
         };
         let handle_mutex = Arc::new(Mutex::new(handle));
-        handle_mutex.lock().unwrap().launch_server(Arc::clone(&world)).expect("Failed to launch server");
+        handle_mutex.lock().unwrap().launch_server(Arc::clone(&world), config).expect("Failed to launch server");
         handle_mutex
     }
 }
\ No newline at end of file
@@ -1,26 +1,281 @@
 use macroquad::prelude::*;
 use macroquad_tiled as tiled;
 use macroquad_platformer::*;
-use rust_mp::message::{ObjectType, RpcCallContainer};
 use rust_mp::{player::*, PLAYER_SIZE_DATA};
-use rust_mp::game_handle::GameHandle;
+use rust_mp::address::resolve_server_address;
+use rust_mp::game_handle::{ConnectError, GameHandle, JoinOptions};
+use rust_mp::server::ServerConfig;
+use rust_mp::room::WorldFactory;
+use rust_mp::spectator::SpectatorCamera;
+use rust_mp::chat::ChatBox;
+use rust_mp::names::display_name;
+use rust_mp::lobby::{draw_lobby, next_character, LobbyEntry};
+use rust_mp::capture::start_capture;
+use rust_mp::timestep::{FixedTimestep, DEFAULT_TICK_RATE, MAX_TICK_RATE, MIN_TICK_RATE};
+use rust_mp::replay::{self, Replay, ReplayPlayback};
+use rust_mp::logging::{self, Target};
+use rust_mp::{log_debug, log_error, log_info, log_warn};
 use std::sync::{Arc, Mutex};
 use std::str::FromStr;
 
 
+/// Environment variable holding a default log filter, overridden by `--log-level`.
+const LOG_ENV_VAR: &str = "RUSTMP_LOG";
+const USAGE: &str = "\
+Usage: game_main <is_server> <host[:port]> <character_type> [--spectate] [--room NAME] [--name NICKNAME] [--max-players N] [--tick-rate HZ] [--record PATH] [--log-level FILTER] [--log-file PATH] [--trace-packets] [--capture PATH]
+       game_main --replay PATH";
+
+struct LaunchOptions {
+    is_server: bool,
+    address: String,
+    character_type: CharacterType,
+    spectate: bool,
+    room: Option<String>,
+    name: Option<String>,
+    server_config: ServerConfig,
+    log_filter: Option<String>,
+    log_file: Option<String>,
+    trace_packets: bool,
+    capture: Option<String>,
+    record: Option<String>,
+    replay: Option<String>,
+    tick_rate: u32,
+}
+
+fn parse_launch_options(args: &[String]) -> Result<LaunchOptions, String> {
+    let replay = (args.get(1).map(String::as_str) == Some("--replay"))
+        .then(|| args.get(2).cloned().ok_or("--replay needs a value"))
+        .transpose()?;
+    if replay.is_none() && args.len() < 4 {
+        return Err("Missing arguments".to_string());
+    }
+    let mut options = LaunchOptions {
+        is_server: replay.is_none() && args[1].parse::<bool>().unwrap_or(false),
+        address: if replay.is_none() { args[2].clone() } else { String::new() },
+        character_type: match replay {
+            Some(_) => CharacterType::Witcher,
+            None => CharacterType::from_str(args[3].as_str()).map_err(|_| format!("Unknown character type '{}'", args[3]))?,
+        },
+        spectate: false,
+        room: None,
+        name: None,
+        server_config: ServerConfig::default(),
+        log_filter: std::env::var(LOG_ENV_VAR).ok(),
+        log_file: None,
+        trace_packets: false,
+        capture: None,
+        record: None,
+        replay,
+        tick_rate: DEFAULT_TICK_RATE,
+    };
+
+    let first_flag = if options.replay.is_some() { 3 } else { 4 };
+    let mut flags = args[first_flag..].iter();
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--spectate" => options.spectate = true,
+            "--name" => {
+                options.name = Some(flags.next().ok_or("--name needs a value")?.clone());
+            },
+            "--room" => {
+                options.room = Some(flags.next().ok_or("--room needs a value")?.clone());
+            },
+            "--max-players" => {
+                let value = flags.next().ok_or("--max-players needs a value")?;
+                options.server_config.max_players = value
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid player limit '{}'", value))?;
+            },
+            "--log-level" => {
+                options.log_filter = Some(flags.next().ok_or("--log-level needs a value")?.clone());
+            },
+            "--log-file" => {
+                options.log_file = Some(flags.next().ok_or("--log-file needs a value")?.clone());
+            },
+            "--trace-packets" => options.trace_packets = true,
+            "--capture" => {
+                options.capture = Some(flags.next().ok_or("--capture needs a value")?.clone());
+            },
+            "--tick-rate" => {
+                let value = flags.next().ok_or("--tick-rate needs a value")?;
+                options.tick_rate = value
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|rate| (MIN_TICK_RATE..=MAX_TICK_RATE).contains(rate))
+                    .ok_or(format!("Tick rate must be between {} and {}", MIN_TICK_RATE, MAX_TICK_RATE))?;
+                options.server_config.tick_rate = options.tick_rate;
+            },
+            "--record" => {
+                options.record = Some(flags.next().ok_or("--record needs a value")?.clone());
+            },
+            other => return Err(format!("Unknown option '{}'", other)),
+        }
+    }
+    Ok(options)
+}
+
+fn configure_logging(options: &LaunchOptions) -> Result<(), String> {
+    if let Some(filter) = &options.log_filter {
+        logging::set_filter(filter)?;
+    }
+    if let Some(path) = &options.log_file {
+        logging::set_log_file(path).map_err(|e| format!("Could not open log file '{}': {}", path, e))?;
+    }
+    logging::set_packet_tracing(options.trace_packets);
+    Ok(())
+}
+
+fn draw_background(background_texture: &Texture2D) {
+    let texture_size = vec2(background_texture.width(),background_texture.height());
+    let screen_size = vec2(screen_width(), screen_height());
+    let screen_aspect = screen_size.x / screen_size.y;
+    let texture_asppect = texture_size.x / texture_size.y;
+    let draw_size = if screen_aspect > texture_asppect {
+            let scale = screen_size.y / texture_size.y;
+            texture_size * scale
+        } else {
+            let scale = screen_size.x / texture_size.x;
+            texture_size * scale
+        };
+    let draw_pos = (screen_size - draw_size) / 2.0;
+
+    draw_texture_ex(
+        background_texture,
+        draw_pos.x,
+        draw_pos.y,
+        WHITE,
+        DrawTextureParams {
+            dest_size: Some (draw_size),
+            ..Default::default()
+        },
+    );
+}
+
+fn player_render_size(character_type: CharacterType, player_size_data: &PlayerSizeData) -> Vec2 {
+    let frame_size = match character_type {
+        CharacterType::Witcher => &player_size_data.witcher.idle.size_frame,
+        CharacterType::Witch => &player_size_data.witch.idle.size_frame,
+    };
+    vec2(frame_size.width / 10.0, frame_size.height / 10.0)
+}
+
+
+const REPLAY_SEEK_STEP: f32 = 5.0;
+const REPLAY_EVENT_WINDOW: f32 = 2.0;
+
+/// Plays a recorded match. Space pauses, Left and Right seek, Up and Down change the speed,
+/// Home restarts and Escape quits.
+async fn run_replay(
+    replay: Replay,
+    background_texture: &Texture2D,
+    tiled_map: &tiled::Map,
+    character_textures: &CharacterTextures,
+    animation_frames: &CharacterAnimationFrames,
+    player_size_data: &PlayerSizeData,
+) {
+    let camera = Camera2D::from_display_rect(Rect::new(0.0, 152.0, 320.0, -152.0));
+    let mut world = World::new();
+    let names = replay.names.clone();
+    let mut playback = ReplayPlayback::new(replay);
+    log_info!(Target::Gameplay, "Playing a replay of {:.1}s", playback.replay.duration());
+
+    loop {
+        if is_key_pressed(KeyCode::Escape) {
+            break;
+        }
+        if is_key_pressed(KeyCode::Space) {
+            playback.toggle_pause();
+        }
+        if is_key_pressed(KeyCode::Right) {
+            playback.seek(playback.time + REPLAY_SEEK_STEP);
+        }
+        if is_key_pressed(KeyCode::Left) {
+            playback.seek(playback.time - REPLAY_SEEK_STEP);
+        }
+        if is_key_pressed(KeyCode::Home) {
+            playback.seek(0.0);
+        }
+        if is_key_pressed(KeyCode::Up) {
+            playback.change_speed(2.0);
+        }
+        if is_key_pressed(KeyCode::Down) {
+            playback.change_speed(0.5);
+        }
+        playback.update(get_frame_time(), &mut world, animation_frames, player_size_data);
+
+        clear_background(BLACK);
+        draw_background(background_texture);
+        set_camera(&camera);
+        tiled_map.draw_tiles("main layer", Rect::new(0.0, 0.0, 320.0, 152.0), None);
+        for player in playback.players.values() {
+            let character_type = player.wrapper.character_type;
+            player.render(
+                character_textures,
+                player_render_size(character_type, player_size_data),
+                character_type,
+                world.actor_pos(player.collider),
+                player_size_data,
+                &display_name(&names, player.wrapper.owner_id),
+            );
+        }
+
+        set_default_camera();
+        let duration = playback.replay.duration();
+        let status = if playback.paused { "paused" } else { "playing" };
+        draw_text(format!("REPLAY {:.1}s / {:.1}s  x{}  {}", playback.time, duration, playback.speed, status), 10.0, 24.0, 24.0, WHITE);
+        let progress = if duration > 0.0 { playback.time / duration } else { 1.0 };
+        draw_rectangle(10.0, 32.0, screen_width() - 20.0, 4.0, DARKGRAY);
+        draw_rectangle(10.0, 32.0, (screen_width() - 20.0) * progress, 4.0, YELLOW);
+        for (index, (time, event)) in playback.recent_events(REPLAY_EVENT_WINDOW).iter().rev().take(5).enumerate() {
+            let object = event.object_id.map(|object_id| format!(" on {}", object_id)).unwrap_or_default();
+            draw_text(format!("{:.1}s {}{:?}{}", time, event.function_name, event.params, object), 10.0, 56.0 + index as f32 * 18.0, 18.0, LIGHTGRAY);
+        }
+        draw_text("Space pause  Left/Right seek  Up/Down speed  Home restart  Esc quit", 10.0, screen_height() - 12.0, 18.0, GRAY);
+
+        next_frame().await;
+    }
+}
+
+
 #[macroquad::main("Platformer")]
 async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let options = match parse_launch_options(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = configure_logging(&options) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    if let Some(path) = &options.capture {
+        if let Err(e) = start_capture(path) {
+            eprintln!("Could not start capture '{}': {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(path) = &options.record {
+        if let Err(e) = replay::start_recording(path) {
+            eprintln!("Could not start recording '{}': {}", path, e);
+            std::process::exit(1);
+        }
+    }
+
     // Print the current directory to debug path issues
-    println!("Current directory: {:?}", std::env::current_dir().unwrap());
+    log_debug!(Target::Assets, "Current directory: {:?}", std::env::current_dir().unwrap());
     
     // Load the tileset with error handling
     let tileset = match load_texture("assets/tileset.png").await {
         Ok(texture) => {
-            println!("Successfully loaded tileset");
+            log_info!(Target::Assets, "Successfully loaded tileset");
             texture
         },
         Err(err) => {
-            eprintln!("Failed to load tileset: {:?}", err);
+            log_error!(Target::Assets, "Failed to load tileset: {:?}", err);
             panic!("Could not load required asset");
         }
     };
@@ -33,11 +288,11 @@ async fn main() {
     // Load the map json with error handling
     let tiled_map_json = match load_string("assets/map.json").await {
         Ok(json) => {
-            println!("Successfully loaded map.json");
+            log_info!(Target::Assets, "Successfully loaded map.json");
             json
         },
         Err(err) => {
-            eprintln!("Failed to load map.json: {:?}", err);
+            log_error!(Target::Assets, "Failed to load map.json: {:?}", err);
             panic!("Could not load required asset");
         }
     };
@@ -45,23 +300,23 @@ async fn main() {
     // Try alternative paths for the background image
     let background_texture = match load_texture("assets/Elements/Loc1.png").await {
         Ok(texture) => {
-            println!("Successfully loaded background from assets/Elements/Loc1.png");
+            log_info!(Target::Assets, "Successfully loaded background from assets/Elements/Loc1.png");
             texture
         },
         Err(_) => match load_texture("Elements/Loc1.png").await {
             Ok(texture) => {
-                println!("Successfully loaded background from Elements/Loc1.png");
+                log_info!(Target::Assets, "Successfully loaded background from Elements/Loc1.png");
                 texture
             },
             Err(_) => match load_texture("Loc1.png").await {
                 Ok(texture) => {
-                    println!("Successfully loaded background from Loc1.png");
+                    log_info!(Target::Assets, "Successfully loaded background from Loc1.png");
                     texture
                 },
                 Err(err) => {
-                    eprintln!("Failed to load background image: {:?}", err);
+                    log_error!(Target::Assets, "Failed to load background image: {:?}", err);
                     // Use a placeholder texture if all attempts fail
-                    println!("Using placeholder background");
+                    log_warn!(Target::Assets, "Using placeholder background");
                     Texture2D::from_rgba8(1, 1, &[0, 0, 0, 255])
                 }
             }
@@ -75,11 +330,11 @@ async fn main() {
         &[]
     ) {
         Ok(map) => {
-            println!("Successfully loaded tiled map");
+            log_info!(Target::Assets, "Successfully loaded tiled map");
             map
         },
         Err(err) => {
-            eprintln!("Failed to load tiled map: {:?}", err);
+            log_error!(Target::Assets, "Failed to load tiled map: {:?}", err);
             panic!("Could not load required map data");
         }
     };
@@ -92,158 +347,254 @@ async fn main() {
             Tile::Empty
         });
     }
-    println!("Created {} static colliders", static_colliders.len());
+    log_debug!(Target::Assets, "Created {} static colliders", static_colliders.len());
 
-    let world = Arc::new(Mutex::new(World::new()));
-    world.lock().unwrap().add_static_tiled_layer(static_colliders, 8., 8., 40, 1);
-    
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 4 {
-        eprintln!("Usage: game_main <is_server> <ip:port> <character_type>");
-        std::process::exit(1);
+    if let Some(path) = &options.replay {
+        let replay = match Replay::load(path) {
+            Ok(replay) => replay,
+            Err(e) => {
+                eprintln!("Could not load replay '{}': {}", path, e);
+                std::process::exit(1);
+            }
+        };
+        run_replay(replay, &background_texture, &tiled_map, &character_textures, &animation_frames, player_size_data).await;
+        return;
     }
 
-    let is_server = args[1].parse::<bool>().unwrap_or(false);
-    let ip_string = args[2].clone();
-    let character_type_str = args[3].clone();
+    let world_factory: WorldFactory = Arc::new(move || {
+        let mut world = World::new();
+        world.add_static_tiled_layer(static_colliders.clone(), 8., 8., 40, 1);
+        world
+    });
+    let world = Arc::new(Mutex::new(world_factory()));
 
-    let character_type = CharacterType::from_str(character_type_str.as_str()).unwrap();
+    let is_server = options.is_server;
 
     let game_handle = if is_server {
-        GameHandle::construct_server(Arc::clone(&world))
+        GameHandle::construct_server(Arc::clone(&world), options.server_config)
     } else {
-        GameHandle::construct_client(ip_string, Arc::clone(&world))
+        let server_address = match resolve_server_address(&options.address) {
+            Ok(address) => address,
+            Err(e) => {
+                eprintln!("Invalid server address: {}", e);
+                std::process::exit(1);
+            }
+        };
+        log_info!(Target::Net, "Connecting to {}", server_address);
+        let join = JoinOptions {
+            spectator: options.spectate,
+            room: options.room.clone(),
+            name: options.name.clone(),
+        };
+        match GameHandle::construct_client(server_address, Arc::clone(&world), join) {
+            Ok(handle) => handle,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(e.exit_code());
+            }
+        }
     };
-    
-    {
-        let player = Player::construct_from_wrapper(
-            DataWrapper {
-                state: PlayerState::Idle,
-                owner_id: 0,
-                object_id: -1,
-                character_type: character_type,
-                position_data: (15.0, 15.0),
-                speed_data: (0.0, 0.0),
-                facing_right: true,
-                
-                
-            },
-            &mut world.lock().unwrap(),
-            &player_size_data,
-        );
-        game_handle.lock().unwrap().add_player(player);
-        println!("Added initial player");
+    game_handle.lock().unwrap().set_world_factory(world_factory);
+    if let (true, Some(name)) = (is_server, &options.name) {
+        game_handle.lock().unwrap().set_host_name(name);
     }
-
-    let mut frame_timer = 0.0;
+    
+    let mut timestep = FixedTimestep::new(options.tick_rate);
+    let mut input = PlayerInput::default();
     let camera = Camera2D::from_display_rect(Rect::new(0.0, 152.0, 320.0, -152.0));
+    let mut spectator_camera = SpectatorCamera::new();
+    let mut chat_box = ChatBox::new();
 
-    println!("Entering game loop");
+    let mut lobby_choice = LobbyEntry { character_type: options.character_type, ready: false };
+    let mut in_match = false;
+
+    log_debug!(Target::Gameplay, "Entering game loop");
    
     
     loop {
-        clear_background(BLACK);
-        let texture_size = vec2(background_texture.width(),background_texture.height());
-        let screen_size = vec2(screen_width(), screen_height());
-        let screen_aspect = screen_size.x / screen_size.y;
-        let texture_asppect = texture_size.x / texture_size.y;
-        let draw_size;
-            if screen_aspect > texture_asppect {
-                let scale = screen_size.y / texture_size.y;
-                draw_size = texture_size * scale; 
+        if let Some(reason) = game_handle.lock().unwrap().kick_reason() {
+            eprintln!("Kicked from the server: {}", reason);
+            std::process::exit(ConnectError::KICKED_EXIT_CODE);
+        }
+        game_handle.lock().unwrap().run_queued_rpcs();
+        if !in_match {
+            let lobby = {
+                let mut game_handle_lock = game_handle.lock().unwrap();
+                game_handle_lock.check_host_migration();
+                game_handle_lock.get_lobby()
+            };
+            if lobby.started {
+                in_match = true;
+                if options.spectate {
+                    log_info!(Target::Gameplay, "Joined as a spectator");
+                } else if game_handle.lock().unwrap().has_owned_player() {
+                    log_info!(Target::Gameplay, "Resumed control of the existing player");
+                } else {
+                    let player = Player::construct_from_wrapper(
+                        DataWrapper {
+                            state: PlayerState::Idle,
+                            owner_id: 0,
+                            object_id: -1,
+                            character_type: lobby_choice.character_type,
+                            position_data: (15.0, 15.0),
+                            speed_data: (0.0, 0.0),
+                            facing_right: true,
+                            health: 100,
+                            score: 0,
+                        },
+                        &mut world.lock().unwrap(),
+                        player_size_data,
+                    );
+                    game_handle.lock().unwrap().add_player(player);
+                    log_info!(Target::Gameplay, "Added initial player");
+                }
             } else {
-                let scale = screen_size.x / texture_size.x;
-                draw_size = texture_size * scale;
+                clear_background(BLACK);
+                if is_key_pressed(KeyCode::C) {
+                    lobby_choice.character_type = next_character(lobby_choice.character_type);
+                }
+                if is_key_pressed(KeyCode::R) {
+                    lobby_choice.ready = !lobby_choice.ready;
+                }
+                {
+                    let mut game_handle_lock = game_handle.lock().unwrap();
+                    game_handle_lock.sync_lobby_choice(lobby_choice);
+                    if is_key_pressed(KeyCode::Enter) && game_handle_lock.is_host() {
+                        game_handle_lock.start_match();
+                    }
+                    let names = game_handle_lock.get_names();
+                    draw_lobby(&lobby, &names, game_handle_lock.get_personal_id(), game_handle_lock.is_host(), options.spectate);
+                }
+                next_frame().await;
+                continue;
             }
-        let draw_pos = (screen_size - draw_size) / 2.0;
-
-            draw_texture_ex(
-            &background_texture,
-            draw_pos.x, 
-            draw_pos.y,
-            WHITE,  
-                DrawTextureParams {
-                dest_size: Some (draw_size),
-                ..Default::default()
-                },
-            );
-        set_camera(&camera);
+        }
 
-        tiled_map.draw_tiles("main layer", Rect::new(0.0, 0.0, 320.0, 152.0), None);
+        if let Some(line) = chat_box.update() {
+            game_handle.lock().unwrap().send_chat(&line);
+        }
+        for message in game_handle.lock().unwrap().take_chat_messages() {
+            chat_box.push(message);
+        }
+        let names = game_handle.lock().unwrap().get_names();
+        if chat_box.is_open() {
+            input = PlayerInput::default();
+        } else {
+            input.sample();
+        }
+        let ticks = timestep.advance(get_frame_time());
 
-        let mut game_handle_lock = game_handle.lock().unwrap();
-        let wrapper_map_mutex = game_handle_lock.get_player_wrapper_map();
-        let mut wrapper_map = wrapper_map_mutex.lock().unwrap();
+        clear_background(BLACK);
+        draw_background(&background_texture);
+        if options.spectate && !chat_box.is_open() {
+            let wrapper_map_mutex = game_handle.lock().unwrap().get_player_wrapper_map();
+            spectator_camera.update(&wrapper_map_mutex.lock().unwrap(), get_frame_time());
+            set_camera(&spectator_camera.camera());
+        } else if options.spectate {
+            set_camera(&spectator_camera.camera());
+        } else {
+            set_camera(&camera);
+        }
 
-        let player_data: Vec<(i32, *mut Player)> = wrapper_map
-            .iter_mut()
-            .map(|(idx, player)| (*idx, player as *mut Player))
-            .collect();
+        tiled_map.draw_tiles("main layer", Rect::new(0.0, 0.0, 320.0, 152.0), None);
 
-        for (player_index, player_ptr) in player_data.iter() {
-            // Create a vector of pointers to other players
-            let mut other_players_ptrs: Vec<*mut Player> = player_data
+        // Inputs go out before the player map is locked, since the server thread takes its own
+        // lock first and then the player map's
+        if ticks > 0 {
+            let mut game_handle_lock = game_handle.lock().unwrap();
+            let personal_id = game_handle_lock.get_personal_id();
+            let owned: Vec<i32> = game_handle_lock
+                .get_player_wrapper_map()
+                .lock()
+                .unwrap()
                 .iter()
-                .filter_map(|(idx, ptr)| {
-                    if idx != player_index {
-                        Some(*ptr)
-                    } else {
-                        None
-                    }
-                })
+                .filter(|(_, player)| player.wrapper.owner_id == personal_id)
+                .map(|(object_id, _)| *object_id)
                 .collect();
-            
-            // Convert pointers to mutable references
-            // SAFETY: This is safe because:
-            // 1. We ensure we're not mutably referencing the same player twice
-            // 2. player_ptr points to a valid Player object from wrapper_map
-            let player = unsafe { &mut **player_ptr };
-            
-            // Create vector of mutable references to other players
-            // SAFETY: Each pointer points to a distinct Player object
-            let mut other_players: Vec<&mut Player> = other_players_ptrs
+            for object_id in owned {
+                game_handle_lock.send_input(object_id, input);
+            }
+        }
+
+        {
+            let mut game_handle_lock = game_handle.lock().unwrap();
+            game_handle_lock.check_host_migration();
+            let personal_id = game_handle_lock.get_personal_id();
+            let wrapper_map_mutex = game_handle_lock.get_player_wrapper_map();
+            let mut wrapper_map = wrapper_map_mutex.lock().unwrap();
+
+            let player_data: Vec<(i32, *mut Player)> = wrapper_map
                 .iter_mut()
-                .map(|ptr| unsafe { &mut **ptr })
+                .map(|(idx, player)| (*idx, player as *mut Player))
                 .collect();
-            
-            player.handle(
-                &mut world.lock().unwrap(),
-                &mut frame_timer,
-                game_handle_lock.get_personal_id(),
-                player.wrapper.character_type,
-                &animation_frames,
-                &mut other_players,
-            );
-            
-            if player.speed_updated {
-                player.speed_updated = false;
-                game_handle_lock.send_motion_update(*player_index, player.wrapper.generate_motion_data());
-            }
-            if player.animation_changed {
-                player.animation_changed = false;
-                game_handle_lock.send_rpc(
-                    RpcCallContainer {
-                        function_name: "animation_force".to_string(),
-                        params: vec![ObjectType::Integer(*player_index), ObjectType::AnimationState(player.wrapper.state)],
+
+            // The host's server thread simulates every player, clients only predict ahead of it
+            let predict = !game_handle_lock.is_host();
+            for _ in 0..ticks {
+                for (player_index, player_ptr) in player_data.iter() {
+                    // SAFETY: player_ptr points to a valid Player object from wrapper_map
+                    let player = unsafe { &mut **player_ptr };
+                    player.input_locked = player.wrapper.owner_id == personal_id && chat_box.is_open();
+                    if !predict {
+                        continue;
                     }
+
+                    // Create a vector of pointers to other players
+                    let mut other_players_ptrs: Vec<*mut Player> = player_data
+                        .iter()
+                        .filter_map(|(idx, ptr)| {
+                            if idx != player_index {
+                                Some(*ptr)
+                            } else {
+                                None
+                            }
+                        })
+                        .collect();
+
+                    // Create vector of mutable references to other players
+                    // SAFETY: Each pointer points to a distinct Player object, and none of them is player_ptr
+                    let mut other_players: Vec<&mut Player> = other_players_ptrs
+                        .iter_mut()
+                        .map(|ptr| unsafe { &mut **ptr })
+                        .collect();
+
+                    player.handle(
+                        &mut world.lock().unwrap(),
+                        personal_id,
+                        &animation_frames,
+                        &mut other_players,
+                        &input,
+                        timestep.dt(),
+                    );
+
+                    // The server sends motion and attack starts for us now
+                    player.speed_updated = false;
+                    player.animation_changed = false;
+                }
+                input.consume_presses();
+            }
+
+            let alpha = timestep.alpha();
+            let locked_world = world.lock().unwrap();
+            for player in wrapper_map.values() {
+                let character_type = player.wrapper.character_type;
+                player.render(
+                    &character_textures,
+                    player_render_size(character_type, player_size_data),
+                    character_type,
+                    player.interpolated_position(&locked_world, alpha),
+                    player_size_data,
+                    &display_name(&names, player.wrapper.owner_id),
                 );
             }
-            
-            let character_type = player.wrapper.character_type;
-            let frame_size = match character_type {
-                CharacterType::Witcher => &player_size_data.witcher.idle.size_frame,
-                CharacterType::Witch => &player_size_data.witch.idle.size_frame,
-            };
-            let player_size = vec2(frame_size.width / 10.0, frame_size.height / 10.0);
-            
-            player.render(
-                &character_textures,
-                player_size,
-                character_type,
-                &world.lock().unwrap(),
-                &player_size_data,
-            );
+            replay::record_frame(&wrapper_map, &locked_world, &names);
+        }
+
+        set_default_camera();
+        if options.spectate {
+            spectator_camera.draw_hud();
         }
+        chat_box.draw(|sender_id| display_name(&names, sender_id));
 
         next_frame().await;
     }
@@ -1,28 +1,51 @@
+// Declared first so its macros are in scope for every other module
+#[macro_use]
+pub mod logging;
+pub mod address;
+pub mod admin;
+pub mod capture;
+pub mod chat;
 pub mod client;
+pub mod connection;
+pub mod lobby;
 pub mod server;
+pub mod session;
+pub mod spectator;
 pub mod message;
+pub mod names;
 pub mod network_sync;
 pub mod player;
+pub mod relevancy;
+pub mod replay;
+pub mod room;
 pub mod game_handle;
+pub mod timestep;
 pub mod rpc_game_callables;
 pub mod rpc_funcs;
 pub mod witch_attack_spikes;
 
 use std::collections::HashMap;
-use std::sync::Arc;
 use once_cell::sync::Lazy;
 use crate::player::PlayerSizeData;
 use crate::rpc_funcs::*;
 use crate::rpc_game_callables::*;
 
+/// Registers `func` under `name`, callable by whoever `policy` allows. See `IntoRpc` for the
+/// signatures that can be registered.
+fn register_rpc<Args, F: IntoRpc<Args>>(map: &mut HashMap<&'static str, RegisteredRpc>, name: &'static str, policy: RpcPolicy, func: F) {
+    map.insert(name, RegisteredRpc { callable: func.into_rpc(), policy });
+}
+
 
 pub static PLAYER_SIZE_DATA: Lazy<PlayerSizeData> = Lazy::new(|| {player::load_player_size_data()});
 
-pub static RPC_FN_TABLE: Lazy<HashMap<&'static str, Arc<dyn RpcCallable>>> = Lazy::new(|| {
-    let mut map: HashMap<&'static str, Arc<dyn RpcCallable>> = HashMap::new();
-    map.insert("animation_force", Arc::new(PlayerStateFn(animation_force)));
+pub static RPC_FN_TABLE: Lazy<HashMap<&'static str, RegisteredRpc>> = Lazy::new(|| {
+    let mut map: HashMap<&'static str, RegisteredRpc> = HashMap::new();
+    register_rpc(&mut map, "animation_force", RpcPolicy::ServerOnly, animation_force);
     map
 });
 
+pub static RPC_IDS: Lazy<RpcIdTable> = Lazy::new(RpcIdTable::local);
+
 pub const SERVER_PORT:u16 = 13882;
 pub const CLIENT_PORT:u16 = 28831;
\ No newline at end of file
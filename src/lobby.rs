@@ -0,0 +1,76 @@
+use macroquad::prelude::*;
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use crate::connection::HOST_ID;
+use crate::names::{display_name, NameMap};
+use crate::player::CharacterType;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LobbyEntry {
+    pub character_type: CharacterType,
+    pub ready: bool,
+}
+
+/// Pre-match state owned by the server and mirrored to every client.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LobbyState {
+    pub entries: BTreeMap<i32, LobbyEntry>,
+    pub started: bool,
+}
+
+impl LobbyState {
+    /// Records a peer's choice, returns true if anything changed.
+    pub fn update(&mut self, peer_id: i32, entry: LobbyEntry) -> bool {
+        self.entries.insert(peer_id, entry) != Some(entry)
+    }
+
+    pub fn leave(&mut self, peer_id: i32) -> bool {
+        self.entries.remove(&peer_id).is_some()
+    }
+
+    pub fn get(&self, peer_id: i32) -> Option<&LobbyEntry> {
+        self.entries.get(&peer_id)
+    }
+
+    pub fn all_ready(&self) -> bool {
+        !self.entries.is_empty() && self.entries.values().all(|entry| entry.ready)
+    }
+}
+
+
+pub fn next_character(character_type: CharacterType) -> CharacterType {
+    match character_type {
+        CharacterType::Witcher => CharacterType::Witch,
+        CharacterType::Witch => CharacterType::Witcher,
+    }
+}
+
+
+pub fn draw_lobby(lobby: &LobbyState, names: &NameMap, personal_id: i32, is_host: bool, spectator: bool) {
+    draw_text("Lobby", 20.0, 40.0, 40.0, WHITE);
+
+    let mut y = 90.0;
+    for (peer_id, entry) in lobby.entries.iter() {
+        let mut label = display_name(names, *peer_id);
+        if *peer_id == HOST_ID {
+            label.push_str(" (host)");
+        }
+        if *peer_id == personal_id && !spectator {
+            label.push_str(" (you)");
+        }
+        let (status, color) = if entry.ready { ("READY", GREEN) } else { ("not ready", GRAY) };
+        draw_text(format!("{:<24} {:<10} {}", label, entry.character_type.to_string(), status), 20.0, y, 24.0, color);
+        y += 30.0;
+    }
+
+    let help = if spectator {
+        "Spectating - waiting for the host to start the match"
+    } else if is_host && lobby.all_ready() {
+        "C: change character   R: toggle ready   Enter: start match"
+    } else if is_host {
+        "C: change character   R: toggle ready   (waiting for everyone to be ready)"
+    } else {
+        "C: change character   R: toggle ready   (the host starts the match)"
+    };
+    draw_text(help, 20.0, screen_height() - 30.0, 22.0, WHITE);
+}
@@ -0,0 +1,212 @@
+use colored::Colorize;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" | "warning" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            other => Err(format!("Unknown log level '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+        f.pad(name)
+    }
+}
+
+/// Subsystem a log line belongs to, each with its own level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Net,
+    Rpc,
+    Gameplay,
+    Assets,
+}
+
+const TARGET_COUNT: usize = 4;
+
+impl Target {
+    fn index(self) -> usize {
+        match self {
+            Target::Net => 0,
+            Target::Rpc => 1,
+            Target::Gameplay => 2,
+            Target::Assets => 3,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Target::Net => "net",
+            Target::Rpc => "rpc",
+            Target::Gameplay => "gameplay",
+            Target::Assets => "assets",
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "net" => Ok(Target::Net),
+            "rpc" => Ok(Target::Rpc),
+            "gameplay" => Ok(Target::Gameplay),
+            "assets" => Ok(Target::Assets),
+            other => Err(format!("Unknown log target '{}'", other)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketDirection {
+    Sent,
+    Received,
+}
+
+
+struct Logger {
+    levels: [Level; TARGET_COUNT],
+    file: Option<File>,
+    trace_packets: bool,
+    started: Instant,
+}
+
+static LOGGER: Lazy<Mutex<Logger>> = Lazy::new(|| {
+    Mutex::new(Logger {
+        levels: [Level::Info; TARGET_COUNT],
+        file: None,
+        trace_packets: false,
+        started: Instant::now(),
+    })
+});
+
+
+/// Applies a filter such as `info`, `net=debug` or `warn,rpc=trace`. A bare level sets every target.
+pub fn set_filter(filter: &str) -> Result<(), String> {
+    let mut levels = LOGGER.lock().unwrap().levels;
+    for directive in filter.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+        match directive.split_once('=') {
+            Some((target, level)) => levels[target.parse::<Target>()?.index()] = level.parse()?,
+            None => levels = [directive.parse()?; TARGET_COUNT],
+        }
+    }
+    LOGGER.lock().unwrap().levels = levels;
+    Ok(())
+}
+
+/// Mirrors every log line, without colors, into the file at `path`.
+pub fn set_log_file(path: &str) -> std::io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    LOGGER.lock().unwrap().file = Some(file);
+    Ok(())
+}
+
+pub fn set_packet_tracing(enabled: bool) {
+    LOGGER.lock().unwrap().trace_packets = enabled;
+}
+
+pub fn packet_tracing() -> bool {
+    LOGGER.lock().unwrap().trace_packets
+}
+
+pub fn enabled(target: Target, level: Level) -> bool {
+    level <= LOGGER.lock().unwrap().levels[target.index()]
+}
+
+
+pub fn log(target: Target, level: Level, args: fmt::Arguments) {
+    let mut logger = LOGGER.lock().unwrap();
+    if level <= logger.levels[target.index()] {
+        write_line(&mut logger, target, level, args);
+    }
+}
+
+fn write_line(logger: &mut Logger, target: Target, level: Level, args: fmt::Arguments) {
+    let elapsed = logger.started.elapsed().as_secs_f32();
+    let line = format!("{:>9.3} {:<5} [{}] {}", elapsed, level, target.name(), args);
+
+    if let Some(file) = logger.file.as_mut() {
+        if writeln!(file, "{}", line).is_err() {
+            logger.file = None;
+        }
+    }
+    match level {
+        Level::Error => eprintln!("{}", line.red()),
+        Level::Warn => eprintln!("{}", line.yellow()),
+        Level::Info => println!("{}", line),
+        Level::Debug | Level::Trace => println!("{}", line.dimmed()),
+    }
+}
+
+/// Writes one compact line per packet when packet tracing is on, whatever the `net` level.
+pub fn trace_packet(direction: PacketDirection, peer: SocketAddr, goal: &str, size: usize, sequence: u32) {
+    let mut logger = LOGGER.lock().unwrap();
+    if !logger.trace_packets {
+        return;
+    }
+    let arrow = match direction {
+        PacketDirection::Sent => "->",
+        PacketDirection::Received => "<-",
+    };
+    write_line(&mut logger, Target::Net, Level::Trace, format_args!("pkt {} {} {} {}B seq={}", arrow, peer, goal, size, sequence));
+}
+
+
+#[macro_export]
+macro_rules! log_error {
+    ($target:expr, $($arg:tt)*) => { $crate::logging::log($target, $crate::logging::Level::Error, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($target:expr, $($arg:tt)*) => { $crate::logging::log($target, $crate::logging::Level::Warn, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($target:expr, $($arg:tt)*) => { $crate::logging::log($target, $crate::logging::Level::Info, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($target:expr, $($arg:tt)*) => { $crate::logging::log($target, $crate::logging::Level::Debug, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! log_trace {
+    ($target:expr, $($arg:tt)*) => { $crate::logging::log($target, $crate::logging::Level::Trace, format_args!($($arg)*)) };
+}
@@ -1,11 +1,22 @@
 use eframe::egui;
-use std::{net::IpAddr, process::Command};
-use rust_mp::SERVER_PORT;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+use rust_mp::address::{format_host_port, resolve_server_address, split_host_port};
+use rust_mp::game_handle::ConnectError;
+use rust_mp::names::{sanitize_display_name, MAX_NAME_LENGTH};
+
+// How long the launcher stays open after launching, to report a rejected connection
+const JOIN_WATCH_PERIOD: Duration = Duration::from_secs(5);
 
 struct LauncherApp {
     text: String,
+    nickname: String,
+    room: String,
     pending_launch: bool,
     is_server: Option<bool>,
+    error_message: Option<String>,
+    spectate: bool,
+    joining: Option<(Child, Instant)>,
     selected_character: rust_mp::player::CharacterType,
 }
 
@@ -13,8 +24,13 @@ impl Default for LauncherApp {
     fn default() -> Self {
         Self {
             text: String::new(),
+            nickname: String::new(),
+            room: String::new(),
             pending_launch: false,
             is_server: None,
+            error_message: None,
+            spectate: false,
+            joining: None,
             selected_character: rust_mp::player::CharacterType::Witcher,
         }
     }
@@ -27,11 +43,57 @@ impl LauncherApp {
     }
 
 
-    fn launch_game_after_closure(&mut self,is_server: bool, ip_string: Option<String>) -> Result<(),std::io::Error>{
-        Command::new("target/debug/game_main")
-            .args(format!("{} {}:{} {}", is_server, ip_string.unwrap(), SERVER_PORT, self.selected_character.to_string()).split_whitespace())
-            .spawn()?;
-        Ok(())
+    fn launch_game_after_closure(&mut self,is_server: bool, address: String) -> Result<Child,std::io::Error>{
+        let mut command = Command::new("target/debug/game_main");
+        command
+            .arg(is_server.to_string())
+            .arg(address)
+            .arg(self.selected_character.to_string());
+        if self.spectate && !is_server {
+            command.arg("--spectate");
+        }
+        if let Some(name) = sanitize_display_name(&self.nickname) {
+            command.arg("--name").arg(name);
+        }
+        if !self.room.trim().is_empty() && !is_server {
+            command.arg("--room").arg(self.room.trim());
+        }
+        command.spawn()
+    }
+
+
+    /// Watches a freshly spawned game and reports why it exited, if it did so right away.
+    fn poll_joining_game(&mut self, ctx: &egui::Context) {
+        let Some((child, started)) = &mut self.joining else {
+            return;
+        };
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                self.error_message = Some(
+                    status
+                        .code()
+                        .and_then(ConnectError::describe_exit_code)
+                        .unwrap_or_else(|| format!("The game exited unexpectedly ({})", status)),
+                );
+                self.joining = None;
+            }
+            Ok(None) if started.elapsed() >= JOIN_WATCH_PERIOD => {
+                self.joining = None;
+                ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+            }
+            Ok(None) => ctx.request_repaint_after(Duration::from_millis(100)),
+            Err(e) => {
+                eprintln!("Could not check on the game process: {}", e);
+                self.joining = None;
+            }
+        }
+    }
+
+    /// Validates the typed address, resolving hostnames, and returns it in `host:port` form.
+    fn validated_address(&self) -> Result<String, String> {
+        let (host, port) = split_host_port(&self.text).map_err(|e| e.to_string())?;
+        resolve_server_address(&self.text).map_err(|e| e.to_string())?;
+        Ok(format_host_port(&host, port))
     }
 
 }
@@ -41,11 +103,23 @@ impl eframe::App for LauncherApp {
         if self.pending_launch {
             self.pending_launch = false;
             let is_server = self.is_server.unwrap_or(false);
-            if (!String::is_empty(&self.text) && self.text.parse::<IpAddr>().is_ok()) || is_server{
-                ctx.send_viewport_cmd(egui::ViewportCommand::Close);
-                self.launch_game_after_closure(is_server, Some(self.text.clone())).expect("Failed to launch game main");
+            let address = if is_server {
+                Ok(format_host_port("localhost", rust_mp::SERVER_PORT))
+            } else {
+                self.validated_address()
+            };
+            match address {
+                Ok(address) => {
+                    self.error_message = None;
+                    let child = self.launch_game_after_closure(is_server, address).expect("Failed to launch game main");
+                    self.joining = Some((child, Instant::now()));
+                }
+                Err(e) => {
+                    self.error_message = Some(e);
+                }
             }
         }
+        self.poll_joining_game(ctx);
         ctx.set_visuals(egui::Visuals {
             dark_mode: true,
             override_text_color: Some(egui::Color32::WHITE),
@@ -100,6 +174,11 @@ impl eframe::App for LauncherApp {
             ui.vertical_centered(|ui| {
                 ui.heading("Game Launcher");
 
+                ui.add(egui::TextEdit::singleline(&mut self.nickname)
+                    .desired_width(f32::INFINITY)
+                    .char_limit(MAX_NAME_LENGTH)
+                    .hint_text("Nickname"));
+
                 if ui.add_sized([ui.available_width(), 30.0], egui::Button::new("Host")).clicked() {
                     self.initiate_game_launch(true);                    
                 }
@@ -108,8 +187,17 @@ impl eframe::App for LauncherApp {
 
                 ui.add(egui::TextEdit::singleline(&mut self.text)
                     .desired_width(f32::INFINITY)
-                    .hint_text("Enter lobby IP"));
-                if ui.add_sized([ui.available_width(), 30.0], egui::Button::new("Join")).clicked() {
+                    .hint_text("Enter lobby address (IP, [IPv6] or hostname)"));
+                if let Some(error_message) = &self.error_message {
+                    ui.colored_label(egui::Color32::from_rgb(255, 110, 110), error_message);
+                }
+                ui.add(egui::TextEdit::singleline(&mut self.room)
+                    .desired_width(f32::INFINITY)
+                    .hint_text("Room (leave empty for the main room)"));
+                ui.checkbox(&mut self.spectate, "Join as spectator");
+                if self.joining.is_some() {
+                    ui.label("Starting game...");
+                } else if ui.add_sized([ui.available_width(), 30.0], egui::Button::new("Join")).clicked() {
                     self.initiate_game_launch(false);
                 }
 
@@ -133,7 +221,7 @@ impl eframe::App for LauncherApp {
 fn main() -> eframe::Result<()> {
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
-            .with_inner_size([300.0, 200.0])
+            .with_inner_size([300.0, 290.0])
             .with_resizable(false),
         centered: true,
         ..Default::default()
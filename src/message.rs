@@ -1,6 +1,13 @@
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
-use crate::player::{DataWrapper, PlayerState};
+use std::net::SocketAddr;
+use crate::chat::ChatMessage;
+use crate::lobby::LobbyState;
+use crate::logging::{self, PacketDirection, Target};
+use crate::names::NameMap;
+use crate::player::{CharacterType, DataWrapper, PlayerInput, PlayerState};
+use crate::rpc_funcs::RpcError;
+use crate::server::ServerConfig;
 
 #[derive(Serialize,Deserialize,Debug,Clone)]
 pub struct MotionDataContainer{
@@ -10,36 +17,169 @@ pub struct MotionDataContainer{
     pub y_speed: f32,
     pub animation_state: PlayerState,
     pub facing_right: bool,
+    pub health: i32,
 }
 
 impl MotionDataContainer {
-    pub fn new(x: f32, y: f32, x_speed: f32, y_speed: f32, animation_state: PlayerState, facing_right: bool) -> Self {
-        MotionDataContainer { x, y, x_speed, y_speed, animation_state, facing_right}
+    pub fn new(x: f32, y: f32, x_speed: f32, y_speed: f32, animation_state: PlayerState, facing_right: bool, health: i32) -> Self {
+        MotionDataContainer { x, y, x_speed, y_speed, animation_state, facing_right, health }
     }
 }
 
 
 
+/// A serialized RPC argument, tagged with a short hash of the Rust type it was encoded from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EncodedValue {
+    pub type_tag: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// Who should run an RPC. The caller runs its own copy when it is among the targets; the server
+/// relays the call to the other peers in the caller's room.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RpcTarget {
+    /// Everyone in the room, the caller and the server included.
+    #[default]
+    All,
+    /// Everyone in the room but the caller.
+    Others,
+    /// Only the server.
+    Server,
+    /// Only the owner of the call's object.
+    Owner,
+    /// Only the peer with this id.
+    Peer(i32),
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RpcCallContainer {
+    /// Resolved from `function_id` on arrival, only the id is sent.
+    #[serde(skip)]
     pub function_name: String,
+    pub function_id: u16,
     pub params: Vec<ObjectType>,
+    pub target: RpcTarget,
+    /// Set when the caller waits for a response; whoever runs the call then answers with an `RpcResponse`.
+    pub call_id: Option<u32>,
+    /// Peer that made the call, filled in by the server when it relays it.
+    pub caller: i32,
+    /// Kept by the server and replayed to peers joining later, for calls with a lasting effect.
+    /// Only honoured for calls targeting `All` or `Others`.
+    pub buffered: bool,
+    /// Object the call is about, set with `with_object`. Player functions run on it, and owner
+    /// checks, `Owner` targeting and buffering go by it.
+    pub object_id: Option<i32>,
+}
+
+impl RpcCallContainer {
+    pub fn new(function_name: &str, params: Vec<ObjectType>) -> Self {
+        RpcCallContainer { function_name: function_name.to_string(), function_id: 0, params, target: RpcTarget::All, call_id: None, caller: 0, buffered: false, object_id: None }
+    }
+
+    /// The response to this call carrying `result`, or `None` when the caller does not wait for one.
+    pub fn response(&self, result: Result<EncodedValue, RpcError>) -> Option<RpcResponse> {
+        self.call_id.map(|call_id| RpcResponse { call_id, caller: self.caller, result })
+    }
+
+    pub fn with_target(mut self, target: RpcTarget) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub fn buffered(mut self) -> Self {
+        self.buffered = true;
+        self
+    }
+
+    pub fn with_object(mut self, object_id: i32) -> Self {
+        self.object_id = Some(object_id);
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RpcResponse {
+    pub call_id: u32,
+    pub caller: i32,
+    pub result: Result<EncodedValue, RpcError>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PeerInfo {
+    pub id: i32,
+    pub address: SocketAddr,
+    pub spectator: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    ServerFull,
+    MatchInProgress,
+    Banned,
+    RoomUnavailable,
+}
+
+impl RejectReason {
+    pub fn code(&self) -> i32 {
+        match self {
+            RejectReason::ServerFull => 1,
+            RejectReason::MatchInProgress => 2,
+            RejectReason::Banned => 3,
+            RejectReason::RoomUnavailable => 4,
+        }
+    }
+
+    pub fn from_code(code: i32) -> Option<RejectReason> {
+        match code {
+            1 => Some(RejectReason::ServerFull),
+            2 => Some(RejectReason::MatchInProgress),
+            3 => Some(RejectReason::Banned),
+            4 => Some(RejectReason::RoomUnavailable),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectReason::ServerFull => write!(f, "The server is full"),
+            RejectReason::MatchInProgress => write!(f, "A match is already in progress"),
+            RejectReason::Banned => write!(f, "You are banned from this server"),
+            RejectReason::RoomUnavailable => write!(f, "That room does not exist and no more rooms can be opened"),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize,Debug,Clone)]
 pub enum ObjectType{
     StringMsg(String),
     Integer(i32),
+    Boolean(bool),
     PlayerMap(HashMap<i32, DataWrapper>),
     Player(DataWrapper),
     MotionData(MotionDataContainer),
     RpcCall(RpcCallContainer),
+    RpcResult(RpcResponse),
+    RpcTable(Vec<String>),
     AnimationState(PlayerState),
+    SessionToken(u64),
+    PeerList(Vec<PeerInfo>),
+    Rejection(RejectReason),
+    Character(CharacterType),
+    Lobby(LobbyState),
+    Chat(ChatMessage),
+    Names(NameMap),
+    Input(PlayerInput),
+    Encoded(EncodedValue),
+    Config(ServerConfig),
 }
 
 #[derive(Serialize, Deserialize,Debug,Clone)]
 pub struct Message {
     target: i32,
+    sequence: u32,
     message_map: HashMap<String, ObjectType>,
 }
 
@@ -47,6 +187,7 @@ impl Message {
     pub fn new(target: i32, message: HashMap<String, ObjectType>) -> Result<Message, Box<dyn std::error::Error>> {
         Ok(Message {
             target,
+            sequence: 0,
             message_map: message,
         })
     }
@@ -54,4 +195,147 @@ impl Message {
     pub fn get_message_map(&self) -> &HashMap<String,ObjectType>{
         &self.message_map
     }
+
+    pub fn get_sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    pub fn set_sequence(&mut self, sequence: u32) {
+        self.sequence = sequence;
+    }
+
+    pub fn goal(&self) -> &str {
+        match self.message_map.get("goal") {
+            Some(ObjectType::StringMsg(goal)) => goal,
+            _ => "?",
+        }
+    }
+}
+
+
+/// Emits a packet trace line for each message when packet tracing is on.
+pub fn trace_messages(direction: PacketDirection, peer: SocketAddr, messages: &[Message]) {
+    if !logging::packet_tracing() {
+        return;
+    }
+    for message in messages {
+        let size = bincode::serialized_size(message).unwrap_or(0) as usize;
+        logging::trace_packet(direction, peer, message.goal(), size, message.sequence);
+    }
+}
+
+
+pub const BATCH_MTU: usize = 1200;
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+
+// bincode prefixes a Vec with its length as a u64
+const BATCH_HEADER_SIZE: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageBatch {
+    messages: Vec<Message>,
+}
+
+/// Packs messages into as few datagrams as fit under `BATCH_MTU`, keeping their order.
+/// A message that is larger than the MTU on its own is sent in a datagram by itself.
+pub fn pack_messages(messages: Vec<Message>) -> Vec<Vec<u8>> {
+    let mut datagrams = Vec::new();
+    let mut current: Vec<Message> = Vec::new();
+    let mut current_size = BATCH_HEADER_SIZE;
+
+    for message in messages {
+        let message_size = match bincode::serialized_size(&message) {
+            Ok(size) => size as usize,
+            Err(e) => {
+                log_error!(Target::Net, "Failed to measure message: {}", e);
+                continue;
+            }
+        };
+
+        if !current.is_empty() && current_size + message_size > BATCH_MTU {
+            datagrams.push(encode_batch(std::mem::take(&mut current)));
+            current_size = BATCH_HEADER_SIZE;
+        }
+        current_size += message_size;
+        current.push(message);
+    }
+
+    if !current.is_empty() {
+        datagrams.push(encode_batch(current));
+    }
+    datagrams.into_iter().flatten().collect()
+}
+
+fn encode_batch(messages: Vec<Message>) -> Option<Vec<u8>> {
+    match bincode::serialize(&MessageBatch { messages }) {
+        Ok(bytes) => Some(bytes),
+        Err(e) => {
+            log_error!(Target::Net, "Failed to serialize message batch: {}", e);
+            None
+        }
+    }
+}
+
+pub fn unpack_datagram(bytes: &[u8]) -> Result<Vec<Message>, bincode::Error> {
+    bincode::deserialize::<MessageBatch>(bytes).map(|batch| batch.messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(sequence: u32, payload_size: usize) -> Message {
+        let mut map = HashMap::new();
+        map.insert(String::from("goal"), ObjectType::StringMsg(String::from("test")));
+        map.insert(String::from("payload"), ObjectType::StringMsg("x".repeat(payload_size)));
+        let mut message = Message::new(1, map).unwrap();
+        message.set_sequence(sequence);
+        message
+    }
+
+    fn unpack_all(datagrams: &[Vec<u8>]) -> Vec<Message> {
+        datagrams.iter().flat_map(|datagram| unpack_datagram(datagram).unwrap()).collect()
+    }
+
+    #[test]
+    fn batches_round_trip() {
+        let datagrams = pack_messages(vec![message(1, 10)]);
+        assert_eq!(datagrams.len(), 1);
+        let messages = unpack_datagram(&datagrams[0]).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].get_sequence(), 1);
+        assert_eq!(messages[0].goal(), "test");
+        assert!(matches!(messages[0].get_message_map().get("payload"), Some(ObjectType::StringMsg(payload)) if payload.len() == 10));
+    }
+
+    #[test]
+    fn order_is_kept_across_datagrams() {
+        let datagrams = pack_messages((0..40).map(|sequence| message(sequence, 100)).collect());
+        assert!(datagrams.len() > 1);
+        let sequences: Vec<u32> = unpack_all(&datagrams).iter().map(Message::get_sequence).collect();
+        assert_eq!(sequences, (0..40).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn datagrams_are_split_at_the_mtu() {
+        let datagrams = pack_messages((0..40).map(|sequence| message(sequence, 100)).collect());
+        assert!(datagrams.iter().all(|datagram| datagram.len() <= BATCH_MTU));
+        // Every datagram but the last was full enough that the next message did not fit
+        let message_size = bincode::serialized_size(&message(0, 100)).unwrap() as usize;
+        for datagram in &datagrams[..datagrams.len() - 1] {
+            assert!(datagram.len() + message_size > BATCH_MTU);
+        }
+    }
+
+    #[test]
+    fn oversized_message_is_sent_alone() {
+        let datagrams = pack_messages(vec![message(0, 10), message(1, BATCH_MTU * 2), message(2, 10)]);
+        assert_eq!(datagrams.len(), 3);
+        assert!(datagrams[1].len() > BATCH_MTU);
+        let batches: Vec<Vec<u32>> = datagrams
+            .iter()
+            .map(|datagram| unpack_datagram(datagram).unwrap().iter().map(Message::get_sequence).collect())
+            .collect();
+        assert_eq!(batches, vec![vec![0], vec![1], vec![2]]);
+    }
 }
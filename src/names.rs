@@ -0,0 +1,29 @@
+use std::collections::HashMap;
+
+pub const MAX_NAME_LENGTH: usize = 16;
+
+/// Display names by peer id, the host's under `HOST_ID`.
+pub type NameMap = HashMap<i32, String>;
+
+/// Keeps letters, digits, spaces and `-_.`, cut to `MAX_NAME_LENGTH` characters.
+pub fn sanitize_display_name(name: &str) -> Option<String> {
+    let cleaned: String = name
+        .trim()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_' | '.'))
+        .take(MAX_NAME_LENGTH)
+        .collect();
+    let cleaned = cleaned.trim().to_string();
+    if cleaned.is_empty() { None } else { Some(cleaned) }
+}
+
+/// Name to show for a peer, falling back to its id when it has not picked one.
+pub fn display_name(names: &NameMap, peer_id: i32) -> String {
+    names.get(&peer_id).cloned().unwrap_or_else(|| format!("Player {}", peer_id))
+}
+
+/// Appends the peer id when another peer already uses the name.
+pub fn unique_name(names: &NameMap, peer_id: i32, wanted: String) -> String {
+    let taken = names.iter().any(|(id, name)| *id != peer_id && name.eq_ignore_ascii_case(&wanted));
+    if taken { format!("{}#{}", wanted, peer_id) } else { wanted }
+}
@@ -32,6 +32,8 @@ pub struct DataWrapper {
     pub position_data: (f32, f32),
     pub speed_data: (f32, f32),
     pub facing_right: bool,
+    pub health: i32,
+    pub score: i32,
 }
 
 impl DataWrapper {
@@ -43,10 +45,14 @@ impl DataWrapper {
             self.speed_data.1,
             self.state,
             self.facing_right,
+            self.health,
         )
     }
 }
 
+/// Seconds each animation frame stays on screen.
+pub const ANIMATION_FRAME_TIME: f32 = 0.1;
+
 #[derive(Debug, Clone, Copy)]
 pub struct Player {
     pub collider: Actor,
@@ -62,6 +68,48 @@ pub struct Player {
     pub animation_changed: bool,
     pub invinvibility_frames: f32,
     pub spikes: Option<Spikes>,
+    pub input_locked: bool,    // Set while the chat box has keyboard focus
+    pub animation_timer: f32,
+    pub previous_position: Vec2, // Collider position before the last tick, for interpolated rendering
+}
+
+/// Keyboard state gathered over the frames between two ticks. Presses stay latched until a tick
+/// consumes them, so none are lost when a frame runs no tick or repeated when it runs several.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct PlayerInput {
+    pub left: bool,
+    pub right: bool,
+    pub jump: bool,
+    pub attack1: bool,
+    pub attack2: bool,
+}
+
+impl PlayerInput {
+    /// Reads the keyboard for this frame.
+    pub fn sample(&mut self) {
+        self.left = is_key_down(KeyCode::A);
+        self.right = is_key_down(KeyCode::D);
+        self.jump |= is_key_pressed(KeyCode::Space);
+        self.attack1 |= is_key_pressed(KeyCode::F);
+        self.attack2 |= is_key_pressed(KeyCode::G);
+    }
+
+    /// Folds in input that arrived before the previous one was simulated: held keys take the
+    /// newer state, presses stay latched.
+    pub fn merge(&mut self, newer: &PlayerInput) {
+        self.left = newer.left;
+        self.right = newer.right;
+        self.jump |= newer.jump;
+        self.attack1 |= newer.attack1;
+        self.attack2 |= newer.attack2;
+    }
+
+    /// Clears the latched presses once a tick has seen them.
+    pub fn consume_presses(&mut self) {
+        self.jump = false;
+        self.attack1 = false;
+        self.attack2 = false;
+    }
 }
 
 pub struct CharacterTextures {
@@ -164,6 +212,12 @@ pub struct CharacterAnimationFrames {
     pub witch: AnimationFrames,   // Кадры для ведьмы
 }
 
+impl Default for CharacterAnimationFrames {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CharacterAnimationFrames {
     pub fn new() -> Self {
         CharacterAnimationFrames {
@@ -225,13 +279,15 @@ impl Player {
         attack_zone.overlaps(&target_collider)
     }
     
-    pub fn take_damage(&mut self, damage: i32) {
+    /// Returns true if this hit killed the player.
+    pub fn take_damage(&mut self, damage: i32) -> bool {
         
         if self.is_dead || self.invinvibility_frames > 0.0 {
-            return;
+            return false;
         }
         
         self.health -= damage;
+        self.wrapper.health = self.health;
         self.invinvibility_frames = 1.5;
         if self.health <= 0 {
             self.health = 0;
@@ -240,10 +296,13 @@ impl Player {
             self.death_frame = 0;
             self.current_frame = 0;
             self.speed = Vec2::ZERO; 
+            self.wrapper.health = 0;
+            return true;
         }
+        false
     }
     
-    pub fn handle_attack(&mut self, other_players: &mut Vec<&mut Player>) {
+    pub fn handle_attack(&mut self, other_players: &mut Vec<&mut Player>, dt: f32) {
         if self.is_dead {
             return;
         }
@@ -253,10 +312,10 @@ impl Player {
         if self.wrapper.character_type == CharacterType::Witch && matches!(self.wrapper.state, PlayerState::Attack1 | PlayerState::Attack2) {
             // Если шипы уже существуют, обновляем их состояние
             if let Some(spikes) = self.spikes.as_mut() {
-                spikes.handle(get_frame_time(), other_players);
+                self.wrapper.score += spikes.handle(dt, other_players);
 
                 // Удаляем шипы, если анимация атаки завершена
-                if self.attack_frame <= 0 {
+                if self.attack_frame == 0 {
                     self.spikes = None;
                 }
             } else {
@@ -302,9 +361,10 @@ impl Player {
             for target in other_players.iter_mut() {
                 if target.wrapper.object_id != self.wrapper.object_id && // Don't attack self
                    !target.is_dead && // Don't attack already dead players
-                   Self::check_attack_collision(self, target)
+                   Self::check_attack_collision(self, target) &&
+                   target.take_damage(damage)
                 {
-                    target.take_damage(damage);
+                    self.wrapper.score += 1;
                 }
             }
         }
@@ -320,11 +380,7 @@ impl Player {
                 ((size.width / 10.0) as i32, (size.height / 10.0) as i32)
             }
         };
-        let position = if wrapper.character_type == CharacterType::Witcher {
-            vec2(wrapper.position_data.0, wrapper.position_data.1)
-        } else {
-            vec2(wrapper.position_data.0, wrapper.position_data.1)
-        };
+        let position = vec2(wrapper.position_data.0, wrapper.position_data.1);
         
         Player {
             collider: world.add_actor(position, width, height),
@@ -334,29 +390,39 @@ impl Player {
             speed_updated: false,
             current_frame: 0,
             facing_right: wrapper.facing_right,
-            health: 100,
-            is_dead: false,
+            health: wrapper.health,
+            is_dead: wrapper.health <= 0,
             death_frame: 0,
             animation_changed: false,
             invinvibility_frames: 0.0,
             spikes: None,
+            input_locked: false,
+            animation_timer: 0.0,
+            previous_position: position,
         }
     }
     
-    pub fn process_input(&mut self, world: &mut World, frame_timer: &mut f32) {
+    pub fn process_input(&mut self, world: &mut World, input: &PlayerInput) {
         // Don't process input if player is dead
         if self.is_dead {
             return;
         }
+        // Keys go to the chat box while it is open, so the player just stops
+        if self.input_locked {
+            if self.wrapper.state != PlayerState::Attack2 {
+                self.speed.x = 0.0;
+            }
+            return;
+        }
         
         let pos = world.actor_pos(self.collider);
         let on_ground = world.collide_check(self.collider, pos + vec2(0., 1.));
 
         if self.wrapper.state != PlayerState::Attack1 && self.wrapper.state != PlayerState::Attack2 {
-            if is_key_down(KeyCode::D) {
+            if input.right {
                 self.speed.x = 100.0;
                 self.facing_right = true;
-            } else if is_key_down(KeyCode::A) {
+            } else if input.left {
                 self.speed.x = -100.0;
                 self.facing_right = false;
             } else {
@@ -372,27 +438,27 @@ impl Player {
             self.speed.x = 0.0;
         }
 
-        if is_key_pressed(KeyCode::Space) && on_ground && 
+        if input.jump && on_ground && 
            self.wrapper.state != PlayerState::Attack1 && 
            self.wrapper.state != PlayerState::Attack2 {
             self.speed.y = -120.0;
         }
         
-        if is_key_pressed(KeyCode::F) && on_ground && 
+        if input.attack1 && on_ground && 
            self.wrapper.state != PlayerState::Attack1 && 
            self.wrapper.state != PlayerState::Attack2 {
             self.wrapper.state = PlayerState::Attack1;
             self.current_frame = 0;
-            *frame_timer = 0.0;
+            self.animation_timer = 0.0;
             self.attack_frame = 0;
         }
 
-        if is_key_pressed(KeyCode::G) && self.wrapper.character_type != CharacterType::Witch && on_ground && 
+        if input.attack2 && self.wrapper.character_type != CharacterType::Witch && on_ground && 
            self.wrapper.state != PlayerState::Attack1 && 
            self.wrapper.state != PlayerState::Attack2 {
             self.wrapper.state = PlayerState::Attack2;
             self.current_frame = 0;
-            *frame_timer = 0.0;
+            self.animation_timer = 0.0;
             self.attack_frame = 0;
             
             if self.facing_right {
@@ -403,13 +469,12 @@ impl Player {
         }
     }
 
-    pub fn apply_physics(&mut self, world: &mut World) {
+    pub fn apply_physics(&mut self, world: &mut World, dt: f32) {
         // No physics for dead players
         if self.is_dead {
             return;
         }
-        
-        let dt = get_frame_time();
+
         let pos = world.actor_pos(self.collider);
         let on_ground = world.collide_check(self.collider, pos + vec2(0., 1.));
 
@@ -421,14 +486,14 @@ impl Player {
         world.move_v(self.collider, self.speed.y * dt);
     }
 
-    pub fn move_player(&mut self, world: &mut World, frame_timer: &mut f32, client_id: i32) {
+    pub fn move_player(&mut self, world: &mut World, client_id: i32, input: &PlayerInput, dt: f32) {
         let old_vel = self.speed;
         
         if client_id == self.get_owner() {
-            self.process_input(world, frame_timer);
+            self.process_input(world, input);
         }
         
-        self.apply_physics(world);
+        self.apply_physics(world, dt);
         
         if client_id == self.get_owner() {
             self.wrapper.facing_right = self.facing_right;
@@ -442,26 +507,29 @@ impl Player {
         }
     }
 
+    /// Runs one simulation tick of `dt` seconds. `input` only applies to the player `client_id` owns.
     pub fn handle(
         &mut self,
         world: &mut World,
-        frame_timer: &mut f32,
         client_id: i32,
-        character_type: CharacterType,
         animation_frames: &CharacterAnimationFrames,
-        other_players: &mut Vec<&mut Player>
+        other_players: &mut Vec<&mut Player>,
+        input: &PlayerInput,
+        dt: f32,
     ) {
+        let character_type = self.wrapper.character_type;
         let prev_state = self.wrapper.state;
-        self.move_player(world, frame_timer, client_id);
+        self.previous_position = world.actor_pos(self.collider);
+        self.move_player(world, client_id, input, dt);
 
-        self.handle_attack(other_players);
+        self.handle_attack(other_players, dt);
         
-        self.invinvibility_frames -= get_frame_time();
+        self.invinvibility_frames -= dt;
         if self.invinvibility_frames < 0.0 { self.invinvibility_frames = 0.0; }
 
         // Handle death animation separately
         if self.is_dead {
-            self.handle_death_animation(frame_timer, character_type, animation_frames);
+            self.handle_death_animation(dt, character_type, animation_frames);
             return;
         }
         
@@ -483,9 +551,9 @@ impl Player {
             };
         }
 
-        *frame_timer += get_frame_time();
-        if *frame_timer >= 0.1 {
-            *frame_timer = 0.0;
+        self.animation_timer += dt;
+        if self.animation_timer >= ANIMATION_FRAME_TIME {
+            self.animation_timer = 0.0;
             self.current_frame += 1;
 
             let frames = match character_type {
@@ -495,13 +563,13 @@ impl Player {
 
             match self.wrapper.state {
                 PlayerState::Running => {
-                    self.current_frame = self.current_frame % frames.run;
+                    self.current_frame %= frames.run;
                 }
                 PlayerState::Idle => {
-                    self.current_frame = self.current_frame % frames.idle;
+                    self.current_frame %= frames.idle;
                 }
                 PlayerState::Jumping => {
-                    self.current_frame = self.current_frame % frames.jumping;
+                    self.current_frame %= frames.jumping;
                 }
                 PlayerState::Attack1 => {
                     if self.attack_frame < frames.attack1 - 1 {
@@ -547,24 +615,43 @@ impl Player {
     // New method to handle death animation
     fn handle_death_animation(
         &mut self, 
-        frame_timer: &mut f32,
+        dt: f32,
         character_type: CharacterType,
         animation_frames: &CharacterAnimationFrames
     ) {
-        *frame_timer += get_frame_time();
-            
-            let frames = match character_type {
-                CharacterType::Witcher => &animation_frames.witcher,
-                CharacterType::Witch => &animation_frames.witch,
-            };
-            
-            // If death animation isn't complete yet
-            if self.death_frame < frames.death - 1 {
-                self.death_frame += 1;
-                self.current_frame = self.death_frame;
-            }
-            // Otherwise stay on the last frame of death animation
-        
+        self.animation_timer += dt;
+        if self.animation_timer < ANIMATION_FRAME_TIME {
+            return;
+        }
+        self.animation_timer = 0.0;
+
+        let frames = match character_type {
+            CharacterType::Witcher => &animation_frames.witcher,
+            CharacterType::Witch => &animation_frames.witch,
+        };
+
+        // If death animation isn't complete yet
+        if self.death_frame < frames.death - 1 {
+            self.death_frame += 1;
+            self.current_frame = self.death_frame;
+        }
+        // Otherwise stay on the last frame of death animation
+    }
+
+    /// Wrapper with the simulated state copied in, ready to replicate or record.
+    pub fn snapshot(&self, world: &World) -> DataWrapper {
+        let position = world.actor_pos(self.collider);
+        let mut wrapper = self.wrapper;
+        wrapper.position_data = (position.x, position.y);
+        wrapper.speed_data = (self.speed.x, self.speed.y);
+        wrapper.facing_right = self.facing_right;
+        wrapper.health = self.health;
+        wrapper
+    }
+
+    /// Collider position blended between the previous and the current tick.
+    pub fn interpolated_position(&self, world: &World, alpha: f32) -> Vec2 {
+        self.previous_position.lerp(world.actor_pos(self.collider), alpha)
     }
     
 
@@ -573,8 +660,9 @@ impl Player {
         textures: &CharacterTextures, 
         player_size: Vec2, 
         character_type: CharacterType, 
-        world: &World, 
-        player_size_data: &PlayerSizeData
+        collider_pos: Vec2, 
+        player_size_data: &PlayerSizeData,
+        display_name: &str,
     ) {
         let player_width = 100.0;  
         let player_height = 100.0; 
@@ -649,7 +737,6 @@ impl Player {
             },
         };
 
-        let collider_pos = world.actor_pos(self.collider);
         let mut collider_size = vec2(player_size.x, player_size.y);
             
         let scale = match character_type {
@@ -657,7 +744,7 @@ impl Player {
             CharacterType::Witch => 0.8,
         };
         
-        collider_size = collider_size * scale;
+        collider_size *= scale;
 
         if character_type == CharacterType::Witch {
             if self.wrapper.state == PlayerState::Attack1 || self.wrapper.state == PlayerState::Attack2 {
@@ -736,6 +823,21 @@ impl Player {
                 Color::new(1.0 - health_percent, health_percent, 0.0, 0.8),
             );
 
+            // Name centered above the health bar
+            let name_params = TextParams {
+                font_size: 16,
+                font_scale: 0.4,
+                color: WHITE,
+                ..Default::default()
+            };
+            let name_width = measure_text(display_name, None, name_params.font_size, name_params.font_scale).width;
+            draw_text_ex(
+                display_name,
+                collider_pos.x - 10.0 + (health_bar_width - name_width) / 2.0,
+                collider_pos.y - 17.0,
+                name_params,
+            );
+
             //Invincibility frame indicator
             if self.invinvibility_frames > 0.0 {
                 draw_rectangle_lines(
@@ -0,0 +1,79 @@
+use std::time::{Duration, Instant};
+use macroquad::math::Vec2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelevancyTier {
+    Near,
+    Mid,
+    Far,
+    Culled,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RelevancyConfig {
+    pub near_distance: f32,
+    pub mid_distance: f32,
+    pub cull_distance: f32,
+    pub mid_interval: Duration,
+    pub far_interval: Duration,
+}
+
+impl Default for RelevancyConfig {
+    fn default() -> Self {
+        RelevancyConfig {
+            near_distance: 120.0,
+            mid_distance: 240.0,
+            cull_distance: 480.0,
+            mid_interval: Duration::from_millis(100),
+            far_interval: Duration::from_millis(400),
+        }
+    }
+}
+
+impl RelevancyConfig {
+    /// Objects are judged from the viewer's own player; peers without a player see everything as near.
+    pub fn tier(&self, viewer: Option<Vec2>, object_position: Vec2) -> RelevancyTier {
+        let Some(viewer) = viewer else {
+            return RelevancyTier::Near;
+        };
+        let distance = viewer.distance(object_position);
+        if distance <= self.near_distance {
+            RelevancyTier::Near
+        } else if distance <= self.mid_distance {
+            RelevancyTier::Mid
+        } else if distance <= self.cull_distance {
+            RelevancyTier::Far
+        } else {
+            RelevancyTier::Culled
+        }
+    }
+
+    pub fn interval(&self, tier: RelevancyTier) -> Option<Duration> {
+        match tier {
+            RelevancyTier::Near => Some(Duration::ZERO),
+            RelevancyTier::Mid => Some(self.mid_interval),
+            RelevancyTier::Far => Some(self.far_interval),
+            RelevancyTier::Culled => None,
+        }
+    }
+}
+
+
+/// What a single peer has been told about a single networked object.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReplicationState {
+    pub spawned: bool,
+    pub dirty: bool,
+    pub last_sent: Option<Instant>,
+}
+
+impl ReplicationState {
+    pub fn update_due(&self, interval: Duration, now: Instant) -> bool {
+        self.dirty && self.last_sent.is_none_or(|last_sent| now.duration_since(last_sent) >= interval)
+    }
+
+    pub fn mark_sent(&mut self, now: Instant) {
+        self.dirty = false;
+        self.last_sent = Some(now);
+    }
+}
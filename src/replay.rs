@@ -0,0 +1,283 @@
+use macroquad::math::vec2;
+use macroquad_platformer::World;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::logging::Target;
+use crate::message::{ObjectType, RpcCallContainer};
+use crate::names::NameMap;
+use crate::player::{CharacterAnimationFrames, ANIMATION_FRAME_TIME, CharacterType, DataWrapper, Player, PlayerState, PlayerSizeData};
+
+const REPLAY_MAGIC: &[u8; 8] = b"RMPRPL02";
+pub const REPLAY_TICK_RATE: u32 = 30;
+pub const MIN_PLAYBACK_SPEED: f32 = 0.25;
+pub const MAX_PLAYBACK_SPEED: f32 = 8.0;
+
+/// An RPC seen during the match. Calls only carry a function id on the wire, so the replay keeps
+/// the name it resolved to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplayEvent {
+    pub function_name: String,
+    pub object_id: Option<i32>,
+    pub params: Vec<ObjectType>,
+}
+
+/// State of every player at one recorded tick, with the RPCs seen since the previous tick.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplayFrame {
+    pub tick: u32,
+    pub time: f32,
+    pub players: Vec<DataWrapper>,
+    pub events: Vec<ReplayEvent>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum ReplayEntry {
+    Names(NameMap),
+    Frame(ReplayFrame),
+}
+
+struct ReplayRecorder {
+    file: File,
+    started: Instant,
+    tick: u32,
+    last_frame: Option<Instant>,
+    names: NameMap,
+    pending_events: Vec<ReplayEvent>,
+}
+
+impl ReplayRecorder {
+    fn write(&mut self, entry: &ReplayEntry) -> io::Result<()> {
+        let encoded = bincode::serialize(entry).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.file.write_all(&encoded)
+    }
+}
+
+static RECORDER: Lazy<Mutex<Option<ReplayRecorder>>> = Lazy::new(|| Mutex::new(None));
+
+
+/// Starts writing a replay of the match to `path`. Frames are streamed to the file as they are
+/// recorded, so the replay survives the game being closed mid-match.
+pub fn start_recording(path: &str) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(REPLAY_MAGIC)?;
+    *RECORDER.lock().unwrap() = Some(ReplayRecorder {
+        file,
+        started: Instant::now(),
+        tick: 0,
+        last_frame: None,
+        names: NameMap::new(),
+        pending_events: Vec::new(),
+    });
+    log_info!(Target::Gameplay, "Recording replay to {}", path);
+    Ok(())
+}
+
+/// Notes an RPC for the next recorded frame. Does nothing unless a recording is running.
+pub fn record_rpc(call: &RpcCallContainer) {
+    if let Some(recorder) = RECORDER.lock().unwrap().as_mut() {
+        recorder.pending_events.push(ReplayEvent { function_name: call.function_name.clone(), object_id: call.object_id, params: call.params.clone() });
+    }
+}
+
+/// Records a frame if a recording is running and a tick is due. Call once per rendered frame.
+pub fn record_frame(players: &HashMap<i32, Player>, world: &World, names: &NameMap) {
+    let mut guard = RECORDER.lock().unwrap();
+    let Some(recorder) = guard.as_mut() else {
+        return;
+    };
+    let now = Instant::now();
+    let interval = Duration::from_secs_f32(1.0 / REPLAY_TICK_RATE as f32);
+    if recorder.last_frame.is_some_and(|last| now.duration_since(last) < interval) {
+        return;
+    }
+    recorder.last_frame = Some(now);
+
+    let mut result = Ok(());
+    if recorder.names != *names {
+        recorder.names = names.clone();
+        result = recorder.write(&ReplayEntry::Names(names.clone()));
+    }
+
+    let mut players: Vec<DataWrapper> = players.values().map(|player| player.snapshot(world)).collect();
+    players.sort_by_key(|wrapper| wrapper.object_id);
+    let frame = ReplayFrame {
+        tick: recorder.tick,
+        time: now.duration_since(recorder.started).as_secs_f32(),
+        players,
+        events: std::mem::take(&mut recorder.pending_events),
+    };
+    recorder.tick += 1;
+    result = result.and_then(|_| recorder.write(&ReplayEntry::Frame(frame)));
+
+    if let Err(e) = result {
+        log_error!(Target::Gameplay, "Stopping replay recording after write failure: {}", e);
+        *guard = None;
+    }
+}
+
+
+/// A replay read back from disk.
+#[derive(Debug, Clone, Default)]
+pub struct Replay {
+    pub frames: Vec<ReplayFrame>,
+    pub names: NameMap,
+}
+
+impl Replay {
+    pub fn load(path: &str) -> io::Result<Replay> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != REPLAY_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a replay file"));
+        }
+
+        let mut replay = Replay::default();
+        loop {
+            match bincode::deserialize_from::<_, ReplayEntry>(&mut reader) {
+                Ok(ReplayEntry::Names(names)) => replay.names.extend(names),
+                Ok(ReplayEntry::Frame(frame)) => replay.frames.push(frame),
+                // A replay cut off mid-write still plays up to its last complete frame
+                Err(e) => match *e {
+                    bincode::ErrorKind::Io(ref io_error) if io_error.kind() == io::ErrorKind::UnexpectedEof => break,
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+                },
+            }
+        }
+        if replay.frames.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Replay holds no frames"));
+        }
+        Ok(replay)
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.frames.last().map(|frame| frame.time).unwrap_or(0.0)
+    }
+
+    /// Index of the last frame at or before `time`.
+    pub fn frame_index_at(&self, time: f32) -> usize {
+        self.frames.partition_point(|frame| frame.time <= time).saturating_sub(1)
+    }
+}
+
+
+/// Playback clock and the players rebuilt from the replay, ready for `Player::render`.
+pub struct ReplayPlayback {
+    pub replay: Replay,
+    pub time: f32,
+    pub speed: f32,
+    pub paused: bool,
+    pub players: HashMap<i32, Player>,
+    animation_timer: f32,
+}
+
+impl ReplayPlayback {
+    pub fn new(replay: Replay) -> Self {
+        let time = replay.frames.first().map(|frame| frame.time).unwrap_or(0.0);
+        ReplayPlayback { replay, time, speed: 1.0, paused: false, players: HashMap::new(), animation_timer: 0.0 }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.time >= self.replay.duration()
+    }
+
+    pub fn toggle_pause(&mut self) {
+        if self.finished() {
+            self.seek(0.0);
+        }
+        self.paused = !self.paused;
+    }
+
+    pub fn seek(&mut self, time: f32) {
+        self.time = time.clamp(0.0, self.replay.duration());
+    }
+
+    pub fn change_speed(&mut self, factor: f32) {
+        self.speed = (self.speed * factor).clamp(MIN_PLAYBACK_SPEED, MAX_PLAYBACK_SPEED);
+    }
+
+    /// Events recorded within the last `window` seconds before the playback time.
+    pub fn recent_events(&self, window: f32) -> Vec<(f32, &ReplayEvent)> {
+        self.replay.frames
+            .iter()
+            .filter(|frame| frame.time <= self.time && frame.time > self.time - window)
+            .flat_map(|frame| frame.events.iter().map(move |event| (frame.time, event)))
+            .collect()
+    }
+
+    /// Advances the clock by `dt` seconds of wall time and moves the players to the replay state,
+    /// interpolating positions between the two surrounding frames.
+    pub fn update(
+        &mut self,
+        dt: f32,
+        world: &mut World,
+        animation_frames: &CharacterAnimationFrames,
+        player_size_data: &PlayerSizeData,
+    ) {
+        let step = if self.paused { 0.0 } else { dt * self.speed };
+        self.seek(self.time + step);
+        if self.finished() {
+            self.paused = true;
+        }
+
+        let index = self.replay.frame_index_at(self.time);
+        let frame = &self.replay.frames[index];
+        let next = self.replay.frames.get(index + 1);
+        let blend = match next {
+            Some(next) if next.time > frame.time => ((self.time - frame.time) / (next.time - frame.time)).clamp(0.0, 1.0),
+            _ => 0.0,
+        };
+
+        self.players.retain(|object_id, _| frame.players.iter().any(|wrapper| wrapper.object_id == *object_id));
+        self.animation_timer += step;
+        let advance = self.animation_timer >= ANIMATION_FRAME_TIME;
+        if advance {
+            self.animation_timer = 0.0;
+        }
+
+        for wrapper in frame.players.iter() {
+            let mut position = vec2(wrapper.position_data.0, wrapper.position_data.1);
+            if let Some(target) = next.and_then(|next| next.players.iter().find(|other| other.object_id == wrapper.object_id)) {
+                position = position.lerp(vec2(target.position_data.0, target.position_data.1), blend);
+            }
+
+            let player = self.players
+                .entry(wrapper.object_id)
+                .or_insert_with(|| Player::construct_from_wrapper(*wrapper, world, player_size_data));
+            world.set_actor_position(player.collider, position);
+
+            if player.wrapper.state != wrapper.state {
+                player.current_frame = 0;
+                player.attack_frame = 0;
+                player.death_frame = 0;
+            }
+            player.wrapper = *wrapper;
+            player.facing_right = wrapper.facing_right;
+            player.health = wrapper.health;
+            player.is_dead = wrapper.state == PlayerState::Death;
+            if advance {
+                advance_animation(player, animation_frames);
+            }
+        }
+    }
+}
+
+fn advance_animation(player: &mut Player, animation_frames: &CharacterAnimationFrames) {
+    let frames = match player.wrapper.character_type {
+        CharacterType::Witcher => &animation_frames.witcher,
+        CharacterType::Witch => &animation_frames.witch,
+    };
+    match player.wrapper.state {
+        PlayerState::Running => player.current_frame = (player.current_frame + 1) % frames.run,
+        PlayerState::Idle => player.current_frame = (player.current_frame + 1) % frames.idle,
+        PlayerState::Jumping => player.current_frame = (player.current_frame + 1) % frames.jumping,
+        PlayerState::Attack1 => player.attack_frame = (player.attack_frame + 1).min(frames.attack1 - 1),
+        PlayerState::Attack2 => player.attack_frame = (player.attack_frame + 1).min(frames.attack2 - 1),
+        PlayerState::Death => player.death_frame = (player.death_frame + 1).min(frames.death - 1),
+    }
+}
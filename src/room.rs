@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use macroquad_platformer::World;
+use crate::lobby::LobbyState;
+use crate::message::RpcCallContainer;
+use crate::player::{DataWrapper, Player, PlayerInput};
+
+/// The room the host plays in. Clients that do not ask for a room end up here.
+pub const DEFAULT_ROOM: &str = "main";
+pub const MAX_ROOM_NAME_LENGTH: usize = 24;
+
+/// Builds a fresh `World` with the level geometry for a newly opened room.
+pub type WorldFactory = Arc<dyn Fn() -> World + Send + Sync>;
+
+/// One independent match hosted by the server.
+#[derive(Clone)]
+pub struct Room {
+    pub name: String,
+    pub world: Arc<Mutex<World>>,
+    pub player_map: Arc<Mutex<HashMap<i32, Player>>>,
+    pub synced_players: Arc<Mutex<HashMap<i32, DataWrapper>>>,
+    /// Latest input from each player's owner, by object id, waiting for the next simulation tick.
+    pub inputs: HashMap<i32, PlayerInput>,
+    /// Calls sent with `buffered` set, in the order they were made, replayed to peers that join
+    /// later. Calls about an object are dropped when it despawns.
+    pub buffered_rpcs: Vec<RpcCallContainer>,
+    pub lobby: LobbyState,
+    pub lobby_dirty: bool,
+    pub last_lobby_broadcast: Instant,
+    /// The host plays in this room and starts its match; other rooms start once everyone is ready.
+    pub hosted: bool,
+}
+
+impl Room {
+    pub fn new(name: &str, world: Arc<Mutex<World>>, player_map: Arc<Mutex<HashMap<i32, Player>>>, hosted: bool) -> Self {
+        Room {
+            name: name.to_string(),
+            world,
+            player_map,
+            synced_players: Arc::new(Mutex::new(HashMap::new())),
+            inputs: HashMap::new(),
+            buffered_rpcs: Vec::new(),
+            lobby: LobbyState::default(),
+            lobby_dirty: false,
+            last_lobby_broadcast: Instant::now(),
+            hosted,
+        }
+    }
+
+    pub fn contains(&self, object_id: i32) -> bool {
+        self.synced_players.lock().unwrap().contains_key(&object_id)
+    }
+
+    pub fn match_in_progress(&self) -> bool {
+        self.lobby.started
+    }
+}
+
+
+/// Trims a requested room name, falling back to the default room when empty.
+pub fn normalize_room_name(name: &str) -> Option<String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Some(DEFAULT_ROOM.to_string());
+    }
+    if name.len() > MAX_ROOM_NAME_LENGTH || !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        return None;
+    }
+    Some(name.to_lowercase())
+}
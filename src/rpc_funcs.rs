@@ -1,87 +1,376 @@
+use std::any::type_name;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use macroquad_platformer::World;
+use crate::connection::HOST_ID;
+use crate::game_handle::GameHandle;
+use crate::logging::Target;
 use crate::RPC_FN_TABLE;
-use crate::message::{ObjectType, RpcCallContainer};
-use crate::player::{Player,PlayerState};
+use crate::message::{EncodedValue, ObjectType, RpcCallContainer, RpcResponse};
+use crate::player::Player;
 
+/// What a function asks the runtime for ahead of its network parameters, going by its signature.
 pub enum RuntimeParams{
     None,
+    /// `&mut Player`, the player named by the call's `object_id`.
     Player,
+    /// `&mut RpcContext`, with the world, every player and the calling peer.
+    Context,
+    /// `&GameHandle`. These calls are queued and run from the game loop, see `GameHandle::run_queued_rpcs`.
+    GameHandle,
 }
 pub trait RpcCallable: Send + Sync {
-    fn call(&self, params: Vec<ObjectType>, runtime_args: &mut [RuntimeArg]);
+    /// Runs the function and returns its encoded return value.
+    fn call(&self, params: Vec<ObjectType>, runtime_args: &mut [RuntimeArg]) -> Result<EncodedValue, RpcError>;
     fn get_runtime_params(&self) -> RuntimeParams;
 }
 
-pub struct NoParamFn(pub fn ());
 
-impl RpcCallable for NoParamFn {
-    fn call(&self, _params: Vec<ObjectType>, _runtime_args: &mut [RuntimeArg]) {
-        (self.0)();
+/// Who may call a registered function. The server and the host can always call anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RpcPolicy {
+    /// Clients may not call it at all.
+    ServerOnly,
+    /// Clients may call it on objects they own, named by the call's `object_id`.
+    OwnerOnly,
+    /// Any client may call it.
+    Anyone,
+}
+
+impl RpcPolicy {
+    /// Whether `caller_id` may call a function with this policy on an object owned by `owner_id`.
+    pub fn allows(self, caller_id: i32, owner_id: Option<i32>) -> bool {
+        caller_id == HOST_ID || match self {
+            RpcPolicy::ServerOnly => false,
+            RpcPolicy::OwnerOnly => owner_id == Some(caller_id),
+            RpcPolicy::Anyone => true,
+        }
     }
-    fn get_runtime_params(&self) -> RuntimeParams {
-        RuntimeParams::None
+}
+
+pub struct RegisteredRpc {
+    pub callable: Arc<dyn RpcCallable>,
+    pub policy: RpcPolicy,
+}
+
+/// Checks that `caller_id` may make this call. `owner_id` is the owner of the object the call
+/// names, if any.
+pub fn authorize(call: &RpcCallContainer, caller_id: i32, owner_id: Option<i32>) -> Result<(), RpcError> {
+    let Some(registered) = RPC_FN_TABLE.get(call.function_name.as_str()) else {
+        return Err(RpcError::UnknownFunction(call.function_name.clone()));
+    };
+    if !registered.policy.allows(caller_id, owner_id) {
+        return Err(RpcError::Unauthorized { function: call.function_name.clone(), policy: format!("{:?}", registered.policy) });
     }
+    Ok(())
 }
 
-pub struct IntParamFn(pub fn(i32));
 
-impl RpcCallable for IntParamFn {
-    fn call(&self, params: Vec<ObjectType>, _runtime_args: &mut [RuntimeArg]) {
-        if let Some(ObjectType::Integer(val)) = params.get(0) {
-            (self.0)(*val);
-        } else {
-            eprintln!("Expected Integer argument for i32 RPC function");
+/// Why a call failed. Travels back to the caller in an `RpcResponse` when the call has a call id.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RpcError {
+    UnknownFunction(String),
+    UnknownObject(Option<i32>),
+    ArgumentCount { expected: usize, found: usize },
+    ArgumentType { index: usize, expected: String, found: String },
+    Encoding(String),
+    MissingRuntimeArg(String),
+    Unauthorized { function: String, policy: String },
+    Timeout,
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::UnknownFunction(name) => write!(f, "unknown RPC function '{}'", name),
+            RpcError::UnknownObject(Some(object_id)) => write!(f, "no object with id {}", object_id),
+            RpcError::UnknownObject(None) => write!(f, "the call does not name an object"),
+            RpcError::ArgumentCount { expected, found } => write!(f, "expected {} arguments, got {}", expected, found),
+            RpcError::ArgumentType { index, expected, found } => write!(f, "argument {} should be {}, got {}", index, expected, found),
+            RpcError::Encoding(e) => write!(f, "could not encode or decode an argument: {}", e),
+            RpcError::MissingRuntimeArg(kind) => write!(f, "no {} was supplied by the runtime", kind),
+            RpcError::Unauthorized { function, policy } => write!(f, "not allowed to call '{}', its policy is {}", function, policy),
+            RpcError::Timeout => write!(f, "no response before the timeout"),
         }
     }
-    fn get_runtime_params(&self) -> RuntimeParams {
-        RuntimeParams::None
+}
+
+impl std::error::Error for RpcError {}
+
+
+/// A value that can travel as an RPC argument. Every serializable type is one: it is packed into
+/// `ObjectType::Encoded` along with its type tag, so a mismatch is reported instead of misread.
+pub trait RpcParam: Sized {
+    fn encode(&self) -> Result<ObjectType, RpcError>;
+    fn decode(param: &ObjectType, index: usize) -> Result<Self, RpcError>;
+}
+
+impl<T: Serialize + DeserializeOwned> RpcParam for T {
+    fn encode(&self) -> Result<ObjectType, RpcError> {
+        encode_value(self).map(ObjectType::Encoded)
+    }
+
+    fn decode(param: &ObjectType, index: usize) -> Result<Self, RpcError> {
+        match param {
+            ObjectType::Encoded(value) => decode_value(value, index),
+            other => Err(RpcError::ArgumentType {
+                index,
+                expected: type_name::<T>().to_string(),
+                found: format!("{:?}", other),
+            }),
+        }
     }
 }
 
 
+/// FNV-1a hash of the type's name. Four bytes on the wire instead of the full path; both ends
+/// run the same build, so they agree on the names.
+pub fn type_tag<T: ?Sized>() -> u32 {
+    type_name::<T>().bytes().fold(0x811c_9dc5, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193))
+}
 
-pub fn invoke_rpc(call: &RpcCallContainer, runtime_args: &mut [RuntimeArg]) {
-    if let Some(func) = RPC_FN_TABLE.get(call.function_name.as_str()) {
-        func.call(call.params.clone(), runtime_args);
-    } else {
-        eprintln!("Unknown RPC function: {}", call.function_name);
+pub fn encode_value<T: Serialize>(value: &T) -> Result<EncodedValue, RpcError> {
+    let bytes = bincode::serialize(value).map_err(|e| RpcError::Encoding(e.to_string()))?;
+    Ok(EncodedValue { type_tag: type_tag::<T>(), bytes })
+}
+
+/// Decodes a value encoded by `encode_value`, checking it was encoded from the same type.
+/// `index` is the argument position reported in errors.
+pub fn decode_value<T: DeserializeOwned>(value: &EncodedValue, index: usize) -> Result<T, RpcError> {
+    if value.type_tag != type_tag::<T>() {
+        return Err(RpcError::ArgumentType {
+            index,
+            expected: type_name::<T>().to_string(),
+            found: format!("a value of another type (tag {:08x})", value.type_tag),
+        });
     }
+    bincode::deserialize(&value.bytes).map_err(|e| RpcError::Encoding(e.to_string()))
+}
+
+
+/// Adapter that exposes a plain function with typed parameters as an `RpcCallable`.
+/// `Args` only records the signature, as `fn(A, B) -> R`, so each arity gets its own impl.
+pub struct TypedFn<F, Args> {
+    func: F,
+    _args: PhantomData<fn(Args)>,
+}
+
+/// Marks functions taking only network parameters.
+pub struct Plain<Args>(PhantomData<Args>);
+/// Marks functions taking the targeted `&mut Player` ahead of their network parameters.
+pub struct WithPlayer<Args>(PhantomData<Args>);
+/// Marks functions taking `&mut RpcContext` ahead of their network parameters.
+pub struct WithContext<Args>(PhantomData<Args>);
+/// Marks functions taking `&GameHandle` ahead of their network parameters.
+pub struct WithHandle<Args>(PhantomData<Args>);
+
+/// Turns a function into a registered RPC. Implemented for functions of up to six serializable
+/// parameters returning a serializable value. The parameters may be preceded by one of
+/// `&mut Player`, `&mut RpcContext` or `&GameHandle`, which the runtime supplies.
+pub trait IntoRpc<Args> {
+    fn into_rpc(self) -> Arc<dyn RpcCallable>;
+}
+
+struct ParamReader<'a> {
+    params: &'a [ObjectType],
+    index: usize,
+}
+
+impl ParamReader<'_> {
+    fn next<T: RpcParam>(&mut self) -> Result<T, RpcError> {
+        let param = self.params.get(self.index).ok_or(RpcError::ArgumentCount { expected: self.index + 1, found: self.params.len() })?;
+        self.index += 1;
+        T::decode(param, self.index - 1)
+    }
+}
+
+fn check_arity(params: &[ObjectType], expected: usize) -> Result<(), RpcError> {
+    if params.len() != expected {
+        return Err(RpcError::ArgumentCount { expected, found: params.len() });
+    }
+    Ok(())
+}
+
+fn runtime_player<'a>(runtime_args: &'a mut [RuntimeArg]) -> Result<&'a mut Player, RpcError> {
+    runtime_args
+        .iter_mut()
+        .find_map(|arg| arg.as_player_mut())
+        .ok_or(RpcError::MissingRuntimeArg("Player".to_string()))
+}
+
+fn runtime_context<'a, 'b>(runtime_args: &'a mut [RuntimeArg<'b>]) -> Result<&'a mut RpcContext<'b>, RpcError> {
+    runtime_args
+        .iter_mut()
+        .find_map(|arg| match arg {
+            RuntimeArg::Context(context) => Some(context),
+            _ => None,
+        })
+        .ok_or(RpcError::MissingRuntimeArg("RpcContext".to_string()))
 }
 
+fn runtime_handle<'a>(runtime_args: &mut [RuntimeArg<'a>]) -> Result<&'a GameHandle, RpcError> {
+    runtime_args
+        .iter()
+        .find_map(|arg| match arg {
+            RuntimeArg::GameHandle(handle) => Some(*handle),
+            _ => None,
+        })
+        .ok_or(RpcError::MissingRuntimeArg("GameHandle".to_string()))
+}
 
-pub struct PlayerStateFn(pub fn(&mut Player, PlayerState));
+/// Implements `RpcCallable` and `IntoRpc` for one arity of one kind of function. Runtime
+/// arguments come first in the signature and are fetched by `$fetch`.
+macro_rules! impl_typed_rpc_kind {
+    ($marker:ident, $runtime:ident, ($($first:ty => $fetch:ident)?); $count:expr $(, $arg:ident: $ty:ident)*) => {
+        #[allow(unused_variables, unused_mut)]
+        impl<F, R $(, $ty)*> RpcCallable for TypedFn<F, $marker<fn($($ty),*) -> R>>
+        where
+            F: Fn($($first,)? $($ty),*) -> R + Send + Sync,
+            R: Serialize,
+            $($ty: RpcParam,)*
+        {
+            fn call(&self, params: Vec<ObjectType>, runtime_args: &mut [RuntimeArg]) -> Result<EncodedValue, RpcError> {
+                check_arity(&params, $count)?;
+                let mut reader = ParamReader { params: &params, index: 0 };
+                $(let $arg: $ty = reader.next()?;)*
+                encode_value(&(self.func)($($fetch(runtime_args)?,)? $($arg),*))
+            }
+            fn get_runtime_params(&self) -> RuntimeParams {
+                RuntimeParams::$runtime
+            }
+        }
 
-#[allow(irrefutable_let_patterns)]
-impl RpcCallable for PlayerStateFn {
-    fn call(&self, params: Vec<ObjectType>, runtime_args: &mut [RuntimeArg]) {
-        if runtime_args.is_empty() {
-            eprintln!("No runtime arguments passed (expected Player)");
-            return;
+        impl<F, R $(, $ty)*> IntoRpc<$marker<fn($($ty),*) -> R>> for F
+        where
+            F: Fn($($first,)? $($ty),*) -> R + Send + Sync + 'static,
+            R: Serialize + 'static,
+            $($ty: RpcParam + 'static,)*
+        {
+            fn into_rpc(self) -> Arc<dyn RpcCallable> {
+                Arc::new(TypedFn::<F, $marker<fn($($ty),*) -> R>> { func: self, _args: PhantomData })
+            }
         }
+    };
+}
+
+macro_rules! impl_typed_rpc {
+    ($count:expr $(, $arg:ident: $ty:ident)*) => {
+        impl_typed_rpc_kind!(Plain, None, (); $count $(, $arg: $ty)*);
+        impl_typed_rpc_kind!(WithPlayer, Player, (&mut Player => runtime_player); $count $(, $arg: $ty)*);
+        impl_typed_rpc_kind!(WithContext, Context, (&mut RpcContext<'_> => runtime_context); $count $(, $arg: $ty)*);
+        impl_typed_rpc_kind!(WithHandle, GameHandle, (&GameHandle => runtime_handle); $count $(, $arg: $ty)*);
+    };
+}
+
+impl_typed_rpc!(0);
+impl_typed_rpc!(1, a: A);
+impl_typed_rpc!(2, a: A, b: B);
+impl_typed_rpc!(3, a: A, b: B, c: C);
+impl_typed_rpc!(4, a: A, b: B, c: C, d: D);
+impl_typed_rpc!(5, a: A, b: B, c: C, d: D, e: E);
+impl_typed_rpc!(6, a: A, b: B, c: C, d: D, e: E, g: G);
+
 
-        
-        let mut_arg = &mut runtime_args[0];
-        
-        let player = if let RuntimeArg::Player(ref mut player) = mut_arg {
-            player
-        } else {
-            eprintln!("First runtime argument was not a Player");
-            return;
-        };
-
-        if let Some(ObjectType::AnimationState(state)) = params.get(0) {
-            (self.0)(player, *state);
-        } else {
-            eprintln!("Expected AnimationState as first parameter");
+/// Gives a function access to the state of the peer running it.
+pub struct RpcContext<'a> {
+    /// Peer that made the call.
+    pub sender_id: i32,
+    pub world: &'a mut World,
+    pub players: &'a mut HashMap<i32, Player>,
+}
+
+impl RpcContext<'_> {
+    pub fn player(&mut self, object_id: i32) -> Result<&mut Player, RpcError> {
+        self.players.get_mut(&object_id).ok_or(RpcError::UnknownObject(Some(object_id)))
+    }
+
+    /// Several players at once, in the order of `object_ids`. Repeated ids are returned once.
+    pub fn players_mut(&mut self, object_ids: &[i32]) -> Result<Vec<&mut Player>, RpcError> {
+        if let Some(missing) = object_ids.iter().find(|id| !self.players.contains_key(id)) {
+            return Err(RpcError::UnknownObject(Some(*missing)));
         }
+        let mut found: Vec<(usize, &mut Player)> = self.players
+            .iter_mut()
+            .filter_map(|(object_id, player)| object_ids.iter().position(|id| id == object_id).map(|index| (index, player)))
+            .collect();
+        found.sort_by_key(|(index, _)| *index);
+        Ok(found.into_iter().map(|(_, player)| player).collect())
     }
-    fn get_runtime_params(&self) -> RuntimeParams {
-        RuntimeParams::Player
+}
+
+
+static QUEUED_CALLS: Lazy<Mutex<Vec<RpcCallContainer>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Runs the call with whatever runtime arguments its function asks for, locking the player map
+/// and then the world only when needed. Calls that want the `GameHandle` are queued instead and
+/// return `None`; the network threads cannot take the handle's lock.
+pub fn execute_rpc(call: &RpcCallContainer, players: &Mutex<HashMap<i32, Player>>, world: &Mutex<World>) -> Option<Result<EncodedValue, RpcError>> {
+    let Some(func) = RPC_FN_TABLE.get(call.function_name.as_str()).map(|registered| &registered.callable) else {
+        log_warn!(Target::Rpc, "Unknown RPC function: {}", call.function_name);
+        return Some(Err(RpcError::UnknownFunction(call.function_name.clone())));
+    };
+    let call = call.clone();
+    let result = match func.get_runtime_params() {
+        RuntimeParams::None => invoke_rpc(&call, &mut []),
+        RuntimeParams::Player => {
+            let mut player_map = players.lock().unwrap();
+            let mut runtime_args = Vec::new();
+            if let Some(player_id) = call.object_id {
+                if let Some(player) = player_map.get_mut(&player_id) {
+                    runtime_args.push(RuntimeArg::Player(player));
+                } else {
+                    log_warn!(Target::Rpc, "Player with ID {} not found", player_id);
+                }
+            } else {
+                log_warn!(Target::Rpc, "Player-based function called without an object id");
+            }
+            invoke_rpc(&call, &mut runtime_args)
+        }
+        RuntimeParams::Context => {
+            let mut player_map = players.lock().unwrap();
+            let mut world = world.lock().unwrap();
+            let context = RpcContext { sender_id: call.caller, world: &mut world, players: &mut player_map };
+            invoke_rpc(&call, &mut [RuntimeArg::Context(context)])
+        }
+        RuntimeParams::GameHandle => {
+            QUEUED_CALLS.lock().unwrap().push(call);
+            return None;
+        }
+    };
+    Some(result)
+}
+
+/// Takes the calls waiting for the `GameHandle`.
+pub fn take_queued_calls() -> Vec<RpcCallContainer> {
+    std::mem::take(&mut *QUEUED_CALLS.lock().unwrap())
+}
+
+
+pub fn invoke_rpc(call: &RpcCallContainer, runtime_args: &mut [RuntimeArg]) -> Result<EncodedValue, RpcError> {
+    let result = match RPC_FN_TABLE.get(call.function_name.as_str()) {
+        Some(registered) => registered.callable.call(call.params.clone(), runtime_args),
+        None => Err(RpcError::UnknownFunction(call.function_name.clone())),
+    };
+    if let Err(e) = &result {
+        log_warn!(Target::Rpc, "RPC {} failed: {}", call.function_name, e);
     }
+    result
 }
 
 
 pub enum RuntimeArg<'a> {
     Player(&'a mut Player),
+    Context(RpcContext<'a>),
+    GameHandle(&'a GameHandle),
 }
 
 #[allow(unreachable_patterns)]
@@ -92,4 +381,305 @@ impl<'a> RuntimeArg<'a> {
             _ => None,
         }
     }
-}
\ No newline at end of file
+}
+
+
+/// Numeric ids for the registered RPCs, so calls carry a `u16` on the wire instead of the
+/// function name. A function's id is its position in the table. The server's table is the one
+/// in force: clients receive it in the handshake.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RpcIdTable {
+    names: Vec<String>,
+}
+
+impl RpcIdTable {
+    /// Table of the functions registered in this build, sorted by name so every copy of the same
+    /// build agrees on the ids.
+    pub fn local() -> Self {
+        let mut names: Vec<String> = RPC_FN_TABLE.keys().map(|name| name.to_string()).collect();
+        names.sort();
+        RpcIdTable { names }
+    }
+
+    pub fn from_names(names: Vec<String>) -> Self {
+        RpcIdTable { names }
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn id_of(&self, function_name: &str) -> Option<u16> {
+        self.names.iter().position(|name| name == function_name).map(|id| id as u16)
+    }
+
+    pub fn name_of(&self, function_id: u16) -> Option<&str> {
+        self.names.get(function_id as usize).map(String::as_str)
+    }
+
+    /// Functions only this table has, and functions only `other` has.
+    pub fn differences(&self, other: &RpcIdTable) -> (Vec<String>, Vec<String>) {
+        let only_here = self.names.iter().filter(|name| !other.names.contains(name)).cloned().collect();
+        let only_there = other.names.iter().filter(|name| !self.names.contains(name)).cloned().collect();
+        (only_here, only_there)
+    }
+
+    /// Logs the functions the two builds do not share. Returns whether the tables match.
+    pub fn check_against(&self, other: &RpcIdTable, peer: &str) -> bool {
+        if self == other {
+            return true;
+        }
+        let (only_here, only_there) = self.differences(other);
+        log_error!(
+            Target::Rpc,
+            "RPC table of {} does not match ours, the builds differ. Only we have: [{}], only they have: [{}]",
+            peer,
+            only_here.join(", "),
+            only_there.join(", "),
+        );
+        false
+    }
+
+    /// Fills in the call's id from its function name before it is sent.
+    pub fn encode(&self, call: &mut RpcCallContainer) -> Result<(), RpcError> {
+        call.function_id = self.id_of(&call.function_name).ok_or_else(|| RpcError::UnknownFunction(call.function_name.clone()))?;
+        Ok(())
+    }
+
+    /// Fills in the function name of a received call from its id.
+    pub fn decode(&self, call: &mut RpcCallContainer) -> Result<(), RpcError> {
+        call.function_name = self
+            .name_of(call.function_id)
+            .ok_or_else(|| RpcError::UnknownFunction(format!("#{}", call.function_id)))?
+            .to_string();
+        Ok(())
+    }
+}
+
+/// Calls waiting for their response, by call id.
+struct PendingCall {
+    deadline: Instant,
+    result: Option<Result<EncodedValue, RpcError>>,
+    waker: Option<Waker>,
+}
+
+static NEXT_CALL_ID: AtomicU32 = AtomicU32::new(1);
+static PENDING_CALLS: Lazy<Mutex<HashMap<u32, PendingCall>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Reserves a call id whose response `RpcFuture` will wait up to `timeout` for.
+pub fn register_call<T>(timeout: Duration) -> RpcFuture<T> {
+    let call_id = NEXT_CALL_ID.fetch_add(1, Ordering::Relaxed);
+    PENDING_CALLS.lock().unwrap().insert(call_id, PendingCall { deadline: Instant::now() + timeout, result: None, waker: None });
+    RpcFuture { call_id, _result: PhantomData }
+}
+
+/// Hands a response to the call waiting for it. Responses after the first, or for calls that
+/// timed out or were dropped, are ignored.
+pub fn complete_call(response: RpcResponse) {
+    let mut pending = PENDING_CALLS.lock().unwrap();
+    let Some(call) = pending.get_mut(&response.call_id) else {
+        log_debug!(Target::Rpc, "Dropping response to call {} nobody waits for", response.call_id);
+        return;
+    };
+    if call.result.is_some() {
+        return;
+    }
+    call.result = Some(response.result);
+    if let Some(waker) = call.waker.take() {
+        waker.wake();
+    }
+}
+
+
+/// The pending result of a call made with `GameHandle::call_rpc`. It can be awaited, or polled
+/// once per frame with `try_take`. The timeout is checked whenever it is polled.
+pub struct RpcFuture<T> {
+    call_id: u32,
+    _result: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> RpcFuture<T> {
+    pub fn call_id(&self) -> u32 {
+        self.call_id
+    }
+
+    /// The result if it has arrived or the call timed out, `None` while still waiting.
+    pub fn try_take(&mut self) -> Option<Result<T, RpcError>> {
+        self.take(None)
+    }
+
+    fn take(&mut self, waker: Option<&Waker>) -> Option<Result<T, RpcError>> {
+        let mut pending = PENDING_CALLS.lock().unwrap();
+        let call = pending.get_mut(&self.call_id)?;
+        if call.result.is_none() && Instant::now() < call.deadline {
+            call.waker = waker.cloned();
+            return None;
+        }
+        let result = pending.remove(&self.call_id)?.result.unwrap_or(Err(RpcError::Timeout));
+        Some(result.and_then(|value| decode_value(&value, 0)))
+    }
+}
+
+impl<T: DeserializeOwned> Future for RpcFuture<T> {
+    type Output = Result<T, RpcError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.take(Some(cx.waker())) {
+            Some(result) => Poll::Ready(result),
+            // Already taken, which only happens when polled again after completing
+            None if !PENDING_CALLS.lock().unwrap().contains_key(&self.call_id) => Poll::Ready(Err(RpcError::Timeout)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for RpcFuture<T> {
+    fn drop(&mut self) {
+        PENDING_CALLS.lock().unwrap().remove(&self.call_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run<Args>(func: impl IntoRpc<Args>, params: Vec<ObjectType>) -> Result<EncodedValue, RpcError> {
+        func.into_rpc().call(params, &mut [])
+    }
+
+    fn encoded<T: Serialize + DeserializeOwned>(value: T) -> ObjectType {
+        value.encode().unwrap()
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let value = encode_value(&(7_i32, String::from("seven"))).unwrap();
+        assert_eq!(decode_value::<(i32, String)>(&value, 0).unwrap(), (7, String::from("seven")));
+    }
+
+    #[test]
+    fn typed_functions_round_trip_at_every_arity() {
+        let result = run(|| 0_i32, vec![]).unwrap();
+        assert_eq!(decode_value::<i32>(&result, 0).unwrap(), 0);
+
+        let result = run(|a: i32| a, vec![encoded(1_i32)]).unwrap();
+        assert_eq!(decode_value::<i32>(&result, 0).unwrap(), 1);
+
+        let result = run(|a: i32, b: u8| a + b as i32, vec![encoded(1_i32), encoded(2_u8)]).unwrap();
+        assert_eq!(decode_value::<i32>(&result, 0).unwrap(), 3);
+
+        let result = run(
+            |a: i32, b: u8, c: String| format!("{}{}{}", a, b, c),
+            vec![encoded(1_i32), encoded(2_u8), encoded(String::from("c"))],
+        ).unwrap();
+        assert_eq!(decode_value::<String>(&result, 0).unwrap(), "12c");
+
+        let result = run(
+            |a: i32, b: i32, c: i32, d: bool| if d { a + b + c } else { 0 },
+            vec![encoded(1_i32), encoded(2_i32), encoded(3_i32), encoded(true)],
+        ).unwrap();
+        assert_eq!(decode_value::<i32>(&result, 0).unwrap(), 6);
+
+        let result = run(
+            |a: f32, b: f32, c: f32, d: f32, e: f32| a + b + c + d + e,
+            vec![encoded(1.0_f32), encoded(2.0_f32), encoded(3.0_f32), encoded(4.0_f32), encoded(5.0_f32)],
+        ).unwrap();
+        assert_eq!(decode_value::<f32>(&result, 0).unwrap(), 15.0);
+
+        let result = run(
+            |a: u8, b: u8, c: u8, d: u8, e: u8, f: u8| vec![a, b, c, d, e, f],
+            (1..=6_u8).map(encoded).collect(),
+        ).unwrap();
+        assert_eq!(decode_value::<Vec<u8>>(&result, 0).unwrap(), vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn wrong_argument_type_is_reported() {
+        let result = run(|a: i32, b: u32| a as u32 + b, vec![encoded(1_i32), encoded(2_i32)]);
+        assert!(matches!(result, Err(RpcError::ArgumentType { index: 1, .. })));
+
+        let result = run(|a: f32| a, vec![encoded(1_i32)]);
+        assert!(matches!(result, Err(RpcError::ArgumentType { index: 0, .. })));
+
+        let result = run(|a: i32| a, vec![ObjectType::Integer(1)]);
+        assert!(matches!(result, Err(RpcError::ArgumentType { index: 0, .. })));
+    }
+
+    #[test]
+    fn wrong_argument_count_is_reported() {
+        let result = run(|a: i32, b: i32| a + b, vec![encoded(1_i32)]);
+        assert_eq!(result, Err(RpcError::ArgumentCount { expected: 2, found: 1 }));
+
+        let result = run(|| (), vec![encoded(1_i32)]);
+        assert_eq!(result, Err(RpcError::ArgumentCount { expected: 0, found: 1 }));
+    }
+
+    const OWNER: i32 = 3;
+    const OTHER: i32 = 4;
+
+    #[test]
+    fn server_only_allows_only_the_host() {
+        assert!(RpcPolicy::ServerOnly.allows(HOST_ID, Some(OWNER)));
+        assert!(RpcPolicy::ServerOnly.allows(HOST_ID, None));
+        assert!(!RpcPolicy::ServerOnly.allows(OWNER, Some(OWNER)));
+        assert!(!RpcPolicy::ServerOnly.allows(OTHER, Some(OWNER)));
+        assert!(!RpcPolicy::ServerOnly.allows(OTHER, None));
+    }
+
+    #[test]
+    fn owner_only_allows_the_host_and_the_owner() {
+        assert!(RpcPolicy::OwnerOnly.allows(HOST_ID, Some(OWNER)));
+        assert!(RpcPolicy::OwnerOnly.allows(HOST_ID, None));
+        assert!(RpcPolicy::OwnerOnly.allows(OWNER, Some(OWNER)));
+        assert!(!RpcPolicy::OwnerOnly.allows(OTHER, Some(OWNER)));
+        assert!(!RpcPolicy::OwnerOnly.allows(OTHER, None));
+    }
+
+    #[test]
+    fn anyone_allows_every_caller() {
+        assert!(RpcPolicy::Anyone.allows(HOST_ID, Some(OWNER)));
+        assert!(RpcPolicy::Anyone.allows(HOST_ID, None));
+        assert!(RpcPolicy::Anyone.allows(OWNER, Some(OWNER)));
+        assert!(RpcPolicy::Anyone.allows(OTHER, Some(OWNER)));
+        assert!(RpcPolicy::Anyone.allows(OTHER, None));
+    }
+
+    #[test]
+    fn authorize_uses_the_registered_policy() {
+        let call = RpcCallContainer::new("animation_force", vec![]).with_object(OWNER);
+        assert_eq!(authorize(&call, HOST_ID, Some(OWNER)), Ok(()));
+        assert!(matches!(authorize(&call, OWNER, Some(OWNER)), Err(RpcError::Unauthorized { .. })));
+
+        let unknown = RpcCallContainer::new("no_such_function", vec![]);
+        assert_eq!(authorize(&unknown, HOST_ID, None), Err(RpcError::UnknownFunction(String::from("no_such_function"))));
+    }
+
+    #[test]
+    fn id_table_maps_names_and_ids() {
+        let table = RpcIdTable::from_names(vec![String::from("a"), String::from("b")]);
+        assert_eq!(table.id_of("b"), Some(1));
+        assert_eq!(table.name_of(0), Some("a"));
+        assert_eq!(table.id_of("c"), None);
+        assert_eq!(table.name_of(2), None);
+
+        let mut call = RpcCallContainer::new("b", vec![]);
+        table.encode(&mut call).unwrap();
+        assert_eq!(call.function_id, 1);
+        call.function_name.clear();
+        table.decode(&mut call).unwrap();
+        assert_eq!(call.function_name, "b");
+
+        call.function_id = 9;
+        assert_eq!(table.decode(&mut call), Err(RpcError::UnknownFunction(String::from("#9"))));
+        assert!(table.encode(&mut RpcCallContainer::new("c", vec![])).is_err());
+    }
+
+    #[test]
+    fn local_id_table_is_sorted() {
+        let table = RpcIdTable::local();
+        let mut sorted = table.names().to_vec();
+        sorted.sort();
+        assert_eq!(table.names(), sorted.as_slice());
+        assert!(table.check_against(&RpcIdTable::local(), "test"));
+    }
+}
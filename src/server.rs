@@ -1,341 +1,1303 @@
+use crate::address::bind_dual_stack;
+use crate::capture;
+use crate::admin::{AdminCommand, BanList, BanTarget, ADMIN_HELP};
+use crate::chat::{sanitize_chat, ChatMessage};
+use crate::connection::{BroadcastFilter, ConnectionState, ConnectionTable, PeerConnection, HOST_ID};
+use crate::lobby::{LobbyEntry, LobbyState};
+use crate::logging::{PacketDirection, Target};
+use crate::names::{display_name, sanitize_display_name, unique_name, NameMap};
 use crate::network_sync::NetworkSync;
-use crate::{CLIENT_PORT, PLAYER_SIZE_DATA, RPC_FN_TABLE, SERVER_PORT};
-use crate::rpc_funcs::{invoke_rpc,RuntimeParams,RuntimeArg};
-use crate::message::{Message, MotionDataContainer, ObjectType, RpcCallContainer};
-use crate::player::{DataWrapper, Player};
+use crate::{PLAYER_SIZE_DATA, RPC_IDS, SERVER_PORT};
+use crate::rpc_funcs::{authorize, complete_call, execute_rpc, RpcError, RpcIdTable, RpcParam};
+use crate::message::{pack_messages, trace_messages, unpack_datagram, Message, ObjectType, PeerInfo, RejectReason, RpcCallContainer, RpcResponse, RpcTarget, MAX_DATAGRAM_SIZE};
+use crate::player::{CharacterAnimationFrames, DataWrapper, Player, PlayerInput, PlayerState};
+use crate::timestep::{FixedTimestep, DEFAULT_TICK_RATE};
+use crate::replay;
+use crate::relevancy::{RelevancyConfig, RelevancyTier};
+use crate::room::{normalize_room_name, Room, WorldFactory, DEFAULT_ROOM};
 use std::collections::HashMap;
-use std::net::{UdpSocket, SocketAddr};
+use std::net::{IpAddr, UdpSocket, SocketAddr};
 use std::io::{Result,ErrorKind};
 use std::sync::{Arc,Mutex};
 use std::thread;
-use std::str::FromStr;
-use std::time::Duration;
-use colored::*;
+use std::time::{Duration, Instant};
 use macroquad::math::vec2;
 use macroquad_platformer::World;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ServerConfig {
+    pub max_players: usize,
+    pub max_spectators: usize,
+    pub max_rooms: usize,
+    pub allow_join_in_progress: bool,
+    pub tick_rate: u32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_players: 8,
+            max_spectators: 4,
+            max_rooms: 4,
+            allow_join_in_progress: true,
+            tick_rate: DEFAULT_TICK_RATE,
+        }
+    }
+}
+
+// The hosting player takes one of the player slots
+const HOST_SLOTS: usize = 1;
 
 #[derive(Clone)]
 pub struct Server {
     socket: Arc<Mutex<UdpSocket>>,
-    user_map: HashMap<i32,SocketAddr>,
-    synced_players: Arc<Mutex<HashMap<i32, DataWrapper>>>, //    🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥🔥
-    world: Arc<Mutex<World>>,
-    player_map_mutex: Arc<Mutex<HashMap<i32,Player>>>,
+    connections: ConnectionTable,
+    rooms: HashMap<String, Room>,
+    world_factory: Option<WorldFactory>,
+    relevancy: RelevancyConfig,
+    peer_list_dirty: bool,
+    config: ServerConfig,
+    bans: BanList,
+    host_chat_inbox: Vec<ChatMessage>,
+    display_names: NameMap,
+    names_dirty: bool,
+    timestep: FixedTimestep,
+    last_simulated: Instant,
 }
 
+// The lobby is re-sent periodically so a lost packet cannot leave a client stuck before the match
+const LOBBY_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
 
 
 
 impl Server {
-    pub fn new(world:Arc<Mutex<World>>, players: Arc<Mutex<HashMap<i32,Player>>>) -> Result<Server> {
-        let server_address = format!("0.0.0.0:{}",SERVER_PORT);
-        let socket = UdpSocket::bind(server_address.clone()).unwrap();
+    pub fn new(world:Arc<Mutex<World>>, players: Arc<Mutex<HashMap<i32,Player>>>, config: ServerConfig) -> Result<Server> {
+        let socket = bind_dual_stack(SERVER_PORT)?;
+        log_info!(Target::Net, "Server bound to: {:?}", socket.local_addr()?);
         socket.set_nonblocking(true)?;
 
+        let mut rooms = HashMap::new();
+        rooms.insert(DEFAULT_ROOM.to_string(), Room::new(DEFAULT_ROOM, world, players, true));
+
         Ok(Server {
             socket: Arc::new(Mutex::new(socket)),
-            user_map: HashMap::new(),
-            synced_players: Arc::new(Mutex::new(HashMap::new())),
-            world: world,
-            player_map_mutex: players,
+            connections: ConnectionTable::new(),
+            rooms,
+            world_factory: None,
+            relevancy: RelevancyConfig::default(),
+            peer_list_dirty: false,
+            config,
+            bans: BanList::load(),
+            host_chat_inbox: Vec::new(),
+            display_names: NameMap::new(),
+            names_dirty: false,
+            timestep: FixedTimestep::new(config.tick_rate),
+            last_simulated: Instant::now(),
         })
     }
 
-    fn gen_new_id(&self) -> i32{
+    /// Object ids are unique across all rooms.
+    pub fn gen_new_player_id(&self) -> i32{
         let mut key:i32 = 1;
-        loop {
-            if !self.user_map.contains_key(&key){
-                break;
-            }
+        while self.rooms.values().any(|room| room.contains(key)) {
             key+=1;
         }
         key
     }
 
-    pub fn gen_new_player_id(&self) -> i32{
-        let mut key:i32 = 1;
-        let synced_players = self.synced_players.lock().unwrap();
-        loop {
-            if !synced_players.contains_key(&key){
-                break;
+
+    pub fn id_to_socket(&self, id: i32) -> Option<SocketAddr> {
+        self.connections.get(id).map(|peer| peer.address)
+    }
+
+
+    pub fn socket_to_id(&self, socket: SocketAddr) -> Option<i32> {
+        self.connections.id_of(socket)
+    }
+
+
+    fn host_room(&self) -> &Room {
+        self.rooms.get(DEFAULT_ROOM).expect("The default room always exists")
+    }
+
+
+    fn host_room_mut(&mut self) -> &mut Room {
+        self.rooms.get_mut(DEFAULT_ROOM).expect("The default room always exists")
+    }
+
+
+    /// Room of a peer; the host and unknown senders belong to the default room.
+    fn room_of_peer(&self, peer_id: Option<i32>) -> String {
+        peer_id
+            .and_then(|id| self.connections.get(id))
+            .map(|peer| peer.room.clone())
+            .unwrap_or_else(|| DEFAULT_ROOM.to_string())
+    }
+
+
+    fn room_of_object(&self, object_id: i32) -> Option<String> {
+        self.rooms.values().find(|room| room.contains(object_id)).map(|room| room.name.clone())
+    }
+
+
+    pub fn get_room(&self, name: &str) -> Option<&Room> {
+        self.rooms.get(name)
+    }
+
+
+    pub fn set_world_factory(&mut self, world_factory: Option<WorldFactory>) {
+        self.world_factory = world_factory;
+    }
+
+
+    /// Finds the requested room, opening it if it does not exist yet.
+    fn room_for_join(&mut self, requested: &str) -> Option<String> {
+        let name = normalize_room_name(requested)?;
+        if self.rooms.contains_key(&name) {
+            return Some(name);
+        }
+        if self.rooms.len() >= self.config.max_rooms {
+            return None;
+        }
+        let factory = self.world_factory.as_ref()?;
+        let world = Arc::new(Mutex::new(factory()));
+        log_info!(Target::Gameplay, "Opened room '{}'", name);
+        self.rooms.insert(name.clone(), Room::new(&name, world, Arc::new(Mutex::new(HashMap::new())), false));
+        Some(name)
+    }
+
+
+    /// Closes rooms other than the host's once nobody is left in them or holding a session there.
+    fn close_empty_rooms(&mut self) {
+        let empty: Vec<String> = self.rooms
+            .values()
+            .filter(|room| !room.hosted && self.connections.room_population(&room.name) == 0)
+            .map(|room| room.name.clone())
+            .collect();
+        for name in empty {
+            log_info!(Target::Gameplay, "Closed empty room '{}'", name);
+            self.rooms.remove(&name);
+        }
+    }
+
+
+    pub fn get_world(&self) -> Arc<Mutex<World>> {
+        Arc::clone(&self.host_room().world)
+    }
+
+
+    pub fn get_synced_players(&self) -> Arc<Mutex<HashMap<i32, DataWrapper>>> {
+        Arc::clone(&self.host_room().synced_players)
+    }
+
+
+    pub fn get_connections(&self) -> &ConnectionTable {
+        &self.connections
+    }
+
+
+    /// Takes over the match after the previous host left. The old host's players are dropped,
+    /// players of `previous_id` (this process as a client) become host-owned, and every other
+    /// known peer gets its id and players reserved until it reconnects.
+    /// Only the promoted peer's room carries over; it becomes the new default room.
+    pub fn adopt_migrated_state(&mut self, previous_id: i32, known_peers: &[PeerInfo], mut lobby: LobbyState, mut names: NameMap) {
+        let room = self.host_room();
+        let mut player_map = room.player_map.lock().unwrap();
+        player_map.retain(|_, player| player.get_owner() != HOST_ID);
+
+        let mut owned_objects: HashMap<i32, Vec<i32>> = HashMap::new();
+        let mut synced_players = room.synced_players.lock().unwrap();
+        synced_players.clear();
+        for (object_id, player) in player_map.iter_mut() {
+            if player.get_owner() == previous_id {
+                player.set_owner(HOST_ID);
             }
-            key+=1;
+            owned_objects.entry(player.get_owner()).or_default().push(*object_id);
+            synced_players.insert(*object_id, player.wrapper);
         }
-        key
+        drop(synced_players);
+        drop(player_map);
+
+        for peer in known_peers.iter().filter(|peer| peer.id != previous_id) {
+            self.connections.reserve(peer.id, DEFAULT_ROOM, owned_objects.remove(&peer.id).unwrap_or_default());
+        }
+
+        lobby.leave(HOST_ID);
+        if let Some(entry) = lobby.entries.remove(&previous_id) {
+            lobby.update(HOST_ID, entry);
+        }
+        let room = self.host_room_mut();
+        room.lobby = lobby;
+        room.lobby_dirty = true;
+
+        names.remove(&HOST_ID);
+        if let Some(name) = names.remove(&previous_id) {
+            names.insert(HOST_ID, name);
+        }
+        self.display_names = names;
+        self.names_dirty = true;
     }
 
 
-    pub fn id_to_socket(&self, id: i32) -> Option<SocketAddr> {
-        if let Some(socket_addr) = self.user_map.get(&id) {
-            Some(*socket_addr)
-        } else {
-            None
+    pub fn get_names(&self) -> NameMap {
+        self.display_names.clone()
+    }
+
+
+    /// Records the name a peer asked for, made unique among the other peers.
+    pub fn set_display_name(&mut self, peer_id: i32, name: &str) {
+        let Some(name) = sanitize_display_name(name) else {
+            return;
+        };
+        let name = unique_name(&self.display_names, peer_id, name);
+        if self.display_names.get(&peer_id) != Some(&name) {
+            self.display_names.insert(peer_id, name);
+            self.names_dirty = true;
         }
     }
 
 
-    pub fn socket_to_id(&self, socket: SocketAddr) -> Option<i32> {
-        self.user_map
+    pub fn get_config(&self) -> ServerConfig {
+        self.config
+    }
+
+
+    pub fn set_config(&mut self, config: ServerConfig) {
+        self.config = config;
+        self.peer_list_dirty = true;
+    }
+
+
+    /// Lobby of the host's room.
+    pub fn get_lobby(&self) -> LobbyState {
+        self.host_room().lobby.clone()
+    }
+
+
+    pub fn update_lobby(&mut self, room_name: &str, peer_id: i32, entry: LobbyEntry) {
+        let Some(room) = self.rooms.get_mut(room_name) else {
+            return;
+        };
+        if room.lobby.update(peer_id, entry) {
+            room.lobby_dirty = true;
+        }
+        // Rooms without the host have nobody to press start
+        if !room.hosted && !room.lobby.started && room.lobby.all_ready() {
+            Self::start_room_match(room);
+        }
+    }
+
+
+    fn start_room_match(room: &mut Room) {
+        log_info!(Target::Gameplay, "Starting the match in room '{}'", room.name);
+        room.lobby.started = true;
+        room.lobby_dirty = true;
+    }
+
+
+    /// Starts the match in the host's room once every player there is ready. Only the host calls this.
+    pub fn start_match(&mut self) -> bool {
+        let room = self.host_room_mut();
+        if room.lobby.started || !room.lobby.all_ready() {
+            return false;
+        }
+        Self::start_room_match(room);
+        true
+    }
+
+
+    /// Bans the address for the duration, or permanently, and kicks any peer connected from it.
+    pub fn ban_address(&mut self, address: IpAddr, duration: Option<Duration>) {
+        self.bans.ban(address, duration);
+        let banned_peers: Vec<i32> = self.connections
             .iter()
-            .find_map(|(id, addr)| if *addr == socket { Some(*id) } else { None })
+            .filter(|(_, peer)| peer.address.ip().to_canonical() == address.to_canonical())
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in banned_peers {
+            self.kick_peer(peer_id, "You have been banned from this server");
+        }
     }
 
 
-    pub fn get_world(&self) -> Arc<Mutex<World>> {
-        Arc::clone(&self.world)
+    pub fn unban_address(&mut self, address: IpAddr) -> bool {
+        self.bans.unban(address)
     }
 
 
-    pub fn get_synced_players(&self) -> Arc<Mutex<HashMap<i32, DataWrapper>>> {
-        Arc::clone(&self.synced_players)
+    /// Disconnects a peer right away, without keeping its session for reconnection.
+    pub fn kick_peer(&mut self, peer_id: i32, reason: &str) -> bool {
+        let Some(address) = self.id_to_socket(peer_id) else {
+            return false;
+        };
+        let mut kick_message: HashMap<String, ObjectType> = HashMap::new();
+        kick_message.insert("goal".to_string(), ObjectType::StringMsg("kicked".to_string()));
+        kick_message.insert("reason".to_string(), ObjectType::StringMsg(reason.to_string()));
+        if let Err(e) = self.send_message(&kick_message, address) {
+            log_warn!(Target::Net, "Failed to notify kicked peer {}: {}", peer_id, e);
+        }
+
+        let Some(peer) = self.connections.remove(peer_id) else {
+            return false;
+        };
+        log_info!(Target::Net, "Kicked peer {} at {}: {}", peer_id, peer.address, reason);
+        for object_id in peer.owned_objects {
+            self.remove_player(object_id);
+        }
+        if let Some(room) = self.rooms.get_mut(&peer.room) {
+            if room.lobby.leave(peer_id) {
+                room.lobby_dirty = true;
+            }
+        }
+        if self.display_names.remove(&peer_id).is_some() {
+            self.names_dirty = true;
+        }
+        self.peer_list_dirty = true;
+        true
     }
 
 
-    pub fn get_user_map(&self) -> &HashMap<i32,SocketAddr> {
-        &self.user_map
+    /// Runs a command from the admin console and returns the text to show the admin.
+    pub fn execute_admin_command(&mut self, command: AdminCommand) -> String {
+        match command {
+            AdminCommand::List => {
+                let mut peers: Vec<(&i32, &PeerConnection)> = self.connections.iter().collect();
+                peers.sort_by_key(|(peer_id, _)| **peer_id);
+                let mut lines = vec![format!("{} peers connected", peers.len())];
+                for (peer_id, peer) in peers {
+                    lines.push(format!(
+                        "  {:>3}  {:<18} {:<40} room {:<10} {:?}{}{}",
+                        peer_id,
+                        display_name(&self.display_names, *peer_id),
+                        peer.address,
+                        peer.room,
+                        peer.state,
+                        peer.rtt.map(|rtt| format!("  {} ms", rtt.as_millis())).unwrap_or_default(),
+                        if peer.is_spectator { "  spectator" } else { "" },
+                    ));
+                }
+                lines.join("\n")
+            },
+            AdminCommand::Kick { peer_id, reason } => {
+                let reason = reason.unwrap_or_else(|| "Kicked by the host".to_string());
+                if self.kick_peer(peer_id, &reason) {
+                    format!("Kicked peer {}", peer_id)
+                } else {
+                    format!("No peer with id {}", peer_id)
+                }
+            },
+            AdminCommand::Ban { target, duration } => {
+                let address = match target {
+                    BanTarget::Address(address) => address,
+                    BanTarget::Peer(peer_id) => match self.id_to_socket(peer_id) {
+                        Some(address) => address.ip().to_canonical(),
+                        None => return format!("No peer with id {}", peer_id),
+                    },
+                };
+                self.ban_address(address, duration);
+                match duration {
+                    Some(duration) => format!("Banned {} for {} seconds", address, duration.as_secs()),
+                    None => format!("Banned {} permanently", address),
+                }
+            },
+            AdminCommand::Unban(address) => {
+                if self.unban_address(address) {
+                    format!("Unbanned {}", address)
+                } else {
+                    format!("{} was not banned", address)
+                }
+            },
+            AdminCommand::Bans => {
+                let bans = self.bans.active();
+                if bans.is_empty() {
+                    return "No active bans".to_string();
+                }
+                bans.iter()
+                    .map(|(address, remaining)| match remaining {
+                        Some(seconds) => format!("  {} ({} seconds left)", address, seconds),
+                        None => format!("  {} (permanent)", address),
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            },
+            AdminCommand::Say(text) => {
+                let Some(text) = sanitize_chat(&text) else {
+                    return "Nothing to say".to_string();
+                };
+                let room_names: Vec<String> = self.rooms.keys().cloned().collect();
+                for room_name in room_names {
+                    self.relay_chat(&room_name, ChatMessage::system(&format!("[Server] {}", text)));
+                }
+                "Message sent".to_string()
+            },
+            AdminCommand::Set { setting, value } => self.apply_setting(&setting, &value),
+            AdminCommand::Help => ADMIN_HELP.to_string(),
+        }
+    }
+
+
+    fn apply_setting(&mut self, setting: &str, value: &str) -> String {
+        let parse_count = |value: &str| value.parse::<usize>().map_err(|_| format!("'{}' is not a number", value));
+        let result = match setting {
+            "max_players" => parse_count(value).map(|count| self.config.max_players = count),
+            "max_spectators" => parse_count(value).map(|count| self.config.max_spectators = count),
+            "max_rooms" => parse_count(value).map(|count| self.config.max_rooms = count),
+            "join_in_progress" => value
+                .parse::<bool>()
+                .map(|allow| self.config.allow_join_in_progress = allow)
+                .map_err(|_| format!("'{}' is not true or false", value)),
+            other => Err(format!("Unknown setting '{}'", other)),
+        };
+        match result {
+            Ok(()) => format!("{} set to {}", setting, value),
+            Err(e) => e,
+        }
     }
 
 
+    fn admission_check(&self, client_address: SocketAddr, spectator: bool, room: &str) -> Option<RejectReason> {
+        if self.bans.is_banned(client_address.ip()) {
+            return Some(RejectReason::Banned);
+        }
+        if spectator {
+            if self.connections.spectator_count() >= self.config.max_spectators {
+                return Some(RejectReason::ServerFull);
+            }
+        } else {
+            let match_in_progress = self.rooms.get(room).is_some_and(|room| room.match_in_progress());
+            if match_in_progress && !self.config.allow_join_in_progress {
+                return Some(RejectReason::MatchInProgress);
+            }
+            if self.connections.player_count() + HOST_SLOTS >= self.config.max_players {
+                return Some(RejectReason::ServerFull);
+            }
+        }
+        None
+    }
+
+
+    pub fn set_relevancy_config(&mut self, relevancy: RelevancyConfig) {
+        self.relevancy = relevancy;
+    }
+
+
+    /// Registers the player in its owner's room and returns the new object id.
     pub fn add_player(&mut self, mut player: Player, owner_id: i32) -> i32{
         let new_id = self.gen_new_player_id();
         player.wrapper.owner_id = owner_id;
+        player.wrapper.object_id = new_id;
+
+        let room_name = self.room_of_peer(Some(owner_id));
+        if let Some(room) = self.rooms.get(&room_name) {
+            room.synced_players.lock().unwrap().insert(new_id, player.wrapper);
+        }
+        if let Some(peer) = self.connections.get_mut(owner_id) {
+            peer.owned_objects.push(new_id);
+        }
+        new_id
+    }
+
+
+    pub fn remove_player(&mut self, object_id: i32) {
+        for room in self.rooms.values_mut() {
+            room.synced_players.lock().unwrap().remove(&object_id);
+            room.player_map.lock().unwrap().remove(&object_id);
+            room.buffered_rpcs.retain(|call| call.object_id != Some(object_id));
+        }
+
+        let mut notify = Vec::new();
+        for (peer_id, peer) in self.connections.iter_mut() {
+            peer.owned_objects.retain(|id| *id != object_id);
+            if let Some(state) = peer.replication.remove(&object_id) {
+                if state.spawned {
+                    notify.push(*peer_id);
+                }
+            }
+        }
+
+        let mut remove_message: HashMap<String, ObjectType> = HashMap::new();
+        remove_message.insert("goal".to_string(), ObjectType::StringMsg("remove_player".to_string()));
+        remove_message.insert("object_id".to_string(), ObjectType::Integer(object_id));
+        self.broadcast(&remove_message, &BroadcastFilter::only(&notify));
+    }
+
+
+    /// Stores the owner's latest input for the object, to be applied on the next tick.
+    pub fn set_input(&mut self, object_id: i32, input: &PlayerInput) {
+        let Some(room_name) = self.room_of_object(object_id) else {
+            return;
+        };
+        if let Some(room) = self.rooms.get_mut(&room_name) {
+            room.inputs.entry(object_id).or_default().merge(input);
+        }
+    }
+
+
+    /// Runs as many fixed ticks as the time since the last call covers. Every player in every
+    /// room is moved, animated and resolves its attacks here, each driven by its owner's input.
+    fn simulate(&mut self) {
+        let now = Instant::now();
+        let ticks = self.timestep.advance(now.duration_since(self.last_simulated).as_secs_f32());
+        self.last_simulated = now;
+        if ticks == 0 {
+            return;
+        }
 
-        self.synced_players.lock().unwrap().insert(new_id, player.wrapper);
-        new_id        
+        let dt = self.timestep.dt();
+        let animation_frames = CharacterAnimationFrames::new();
+        let mut attack_starts: Vec<(String, i32, i32, PlayerState)> = Vec::new();
+
+        for room in self.rooms.values_mut() {
+            // Same order as the game loop and the RPC runtime: the player map before the world
+            let mut player_map = room.player_map.lock().unwrap();
+            let mut world = room.world.lock().unwrap();
+            let object_ids: Vec<i32> = player_map.keys().copied().collect();
+
+            for _ in 0..ticks {
+                for object_id in object_ids.iter() {
+                    let Some(mut player) = player_map.remove(object_id) else {
+                        continue;
+                    };
+                    let input = room.inputs.get(object_id).copied().unwrap_or_default();
+                    let mut other_players: Vec<&mut Player> = player_map.values_mut().collect();
+                    player.handle(&mut world, player.wrapper.owner_id, &animation_frames, &mut other_players, &input, dt);
+
+                    player.speed_updated = false;
+                    if player.animation_changed {
+                        player.animation_changed = false;
+                        attack_starts.push((room.name.clone(), player.wrapper.owner_id, *object_id, player.wrapper.state));
+                    }
+                    player_map.insert(*object_id, player);
+                }
+                room.inputs.values_mut().for_each(PlayerInput::consume_presses);
+            }
+        }
+
+        // Owners start their attack animation locally, everyone else hears about it here
+        for (room_name, owner_id, object_id, state) in attack_starts {
+            let state = match state.encode() {
+                Ok(state) => state,
+                Err(e) => {
+                    log_error!(Target::Rpc, "Could not encode animation state: {}", e);
+                    continue;
+                }
+            };
+            let call = RpcCallContainer::new("animation_force", vec![state]).with_object(object_id);
+            replay::record_rpc(&call);
+            self.send_rpc(&BroadcastFilter::excluding(&[owner_id]).in_room(&room_name), call);
+        }
+        self.refresh_synced_players();
     }
 
 
-    pub fn send_motion_update(&self, target_vector: Vec<&SocketAddr>, object_id: i32, motion_data: MotionDataContainer) {
-        let mut message = HashMap::new();
-        message.insert("goal".to_string(), ObjectType::StringMsg("motion_update_broadcast".to_string()));
-        message.insert("object_id".to_string(), ObjectType::Integer(object_id));
-        message.insert("motion_data".to_string(), ObjectType::MotionData(motion_data));
+    /// Copies the simulated state into the synced wrappers and marks changed objects for
+    /// replication to the peers in their room.
+    fn refresh_synced_players(&mut self) {
+        let mut changed: Vec<(String, i32)> = Vec::new();
+        for room in self.rooms.values() {
+            let player_map = room.player_map.lock().unwrap();
+            let world = room.world.lock().unwrap();
+            let mut synced_players = room.synced_players.lock().unwrap();
+            for (object_id, wrapper) in synced_players.iter_mut() {
+                let Some(player) = player_map.get(object_id) else {
+                    continue;
+                };
+                let snapshot = player.snapshot(&world);
+                if snapshot.position_data != wrapper.position_data
+                    || snapshot.speed_data != wrapper.speed_data
+                    || snapshot.state != wrapper.state
+                    || snapshot.facing_right != wrapper.facing_right
+                    || snapshot.health != wrapper.health
+                {
+                    changed.push((room.name.clone(), *object_id));
+                }
+                *wrapper = snapshot;
+            }
+        }
 
-        for target in target_vector.iter(){
-            if let Err(e) = self.send_message(&message, **target){
-                eprintln!("Failed to send message: {}", e);
+        for (room_name, object_id) in changed {
+            for peer_id in self.connections.targets(&BroadcastFilter::all().in_room(&room_name)) {
+                if let Some(peer) = self.connections.get_mut(peer_id) {
+                    peer.replication.entry(object_id).or_default().dirty = true;
+                }
             }
         }
     }
 
 
-    pub fn send_rpc(&self, target_vector: Vec<&SocketAddr>, rpc_data: RpcCallContainer) {
+    fn replicate_objects(&mut self) {
+        let room_players: HashMap<String, HashMap<i32, DataWrapper>> = self.rooms
+            .iter()
+            .map(|(name, room)| (name.clone(), room.synced_players.lock().unwrap().clone()))
+            .collect();
+        let room_buffered: HashMap<String, Vec<RpcCallContainer>> = self.rooms
+            .iter()
+            .map(|(name, room)| (name.clone(), room.buffered_rpcs.clone()))
+            .collect();
+        let now = Instant::now();
+        let mut outgoing: Vec<(i32, HashMap<String, ObjectType>)> = Vec::new();
+
+        for (peer_id, peer) in self.connections.iter_mut() {
+            if peer.state != ConnectionState::Connected {
+                continue;
+            }
+            let Some(synced_players) = room_players.get(&peer.room) else {
+                continue;
+            };
+            let viewer = peer.owned_objects
+                .iter()
+                .find_map(|id| synced_players.get(id))
+                .map(|wrapper| vec2(wrapper.position_data.0, wrapper.position_data.1));
+
+            // Owners get their own objects too, to correct their local prediction
+            for (object_id, wrapper) in synced_players.iter() {
+                let tier = self.relevancy.tier(viewer, vec2(wrapper.position_data.0, wrapper.position_data.1));
+                let owned = peer.owned_objects.contains(object_id);
+                let state = peer.replication.entry(*object_id).or_default();
+                let Some(interval) = self.relevancy.interval(tier) else {
+                    // Out of range: despawn it, add_player goes out again once it comes back
+                    if state.spawned && !owned {
+                        let mut remove_message: HashMap<String, ObjectType> = HashMap::new();
+                        remove_message.insert("goal".to_string(), ObjectType::StringMsg("remove_player".to_string()));
+                        remove_message.insert("object_id".to_string(), ObjectType::Integer(*object_id));
+                        outgoing.push((*peer_id, remove_message));
+                        state.spawned = false;
+                    }
+                    continue;
+                };
+
+                if !state.spawned && owned {
+                    // The owner built the object itself, it only needs the motion updates
+                    state.spawned = true;
+                    state.mark_sent(now);
+                } else if !state.spawned {
+                    let mut add_player_message: HashMap<String, ObjectType> = HashMap::new();
+                    add_player_message.insert("goal".to_string(), ObjectType::StringMsg("add_player".to_string()));
+                    add_player_message.insert("player".to_string(), ObjectType::Player(*wrapper));
+                    outgoing.push((*peer_id, add_player_message));
+                    // A freshly spawned object needs the lasting calls made on it so far
+                    for call in room_buffered[&peer.room].iter().filter(|call| call.object_id == Some(*object_id)) {
+                        outgoing.extend(Self::rpc_message(call.clone()).map(|message| (*peer_id, message)));
+                    }
+                    state.spawned = true;
+                    state.mark_sent(now);
+                } else if state.update_due(interval, now) {
+                    let mut motion_message: HashMap<String, ObjectType> = HashMap::new();
+                    motion_message.insert("goal".to_string(), ObjectType::StringMsg("motion_update_broadcast".to_string()));
+                    motion_message.insert("object_id".to_string(), ObjectType::Integer(*object_id));
+                    motion_message.insert("motion_data".to_string(), ObjectType::MotionData(wrapper.generate_motion_data()));
+                    outgoing.push((*peer_id, motion_message));
+                    state.mark_sent(now);
+                }
+            }
+        }
+
+        for (peer_id, message) in outgoing {
+            if let Err(e) = self.send_to_peer(&message, peer_id) {
+                log_error!(Target::Net, "Failed to send message: {}", e);
+            }
+        }
+    }
+
+
+    /// Sends a chat line to everyone in the room, the host's own log included when it is the host's room.
+    pub fn relay_chat(&mut self, room_name: &str, message: ChatMessage) {
+        if room_name == DEFAULT_ROOM {
+            self.host_chat_inbox.push(message.clone());
+        }
+        let mut chat_message: HashMap<String, ObjectType> = HashMap::new();
+        chat_message.insert("goal".to_string(), ObjectType::StringMsg("chat_message".to_string()));
+        chat_message.insert("message".to_string(), ObjectType::Chat(message));
+        self.broadcast(&chat_message, &BroadcastFilter::all().in_room(room_name));
+    }
+
+
+    /// Chat lines that reached the host's room since the last call.
+    pub fn take_host_chat(&mut self) -> Vec<ChatMessage> {
+        std::mem::take(&mut self.host_chat_inbox)
+    }
+
+
+    pub fn send_rpc(&mut self, filter: &BroadcastFilter, rpc_data: RpcCallContainer) {
+        if let Some(message) = Self::rpc_message(rpc_data) {
+            self.broadcast(&message, filter);
+        }
+    }
+
+
+    fn rpc_message(mut rpc_data: RpcCallContainer) -> Option<HashMap<String, ObjectType>> {
+        if let Err(e) = RPC_IDS.encode(&mut rpc_data) {
+            log_error!(Target::Rpc, "Not sending RPC: {}", e);
+            return None;
+        }
         let mut message: HashMap<String, ObjectType> = HashMap::new();
         message.insert("goal".to_string(), ObjectType::StringMsg("rpc_call".to_string()));
         message.insert("rpc_data".to_string(), ObjectType::RpcCall(rpc_data));
+        Some(message)
+    }
+
+
+    /// Sends a late joiner the buffered calls of its room that are not about an object, or are
+    /// about one of `spawned`. Calls about objects it meets later go out when they spawn for it.
+    fn replay_buffered_rpcs(&mut self, peer_id: i32, spawned: &HashMap<i32, DataWrapper>) {
+        let room_name = self.room_of_peer(Some(peer_id));
+        let Some(room) = self.rooms.get(&room_name) else {
+            return;
+        };
+        let calls: Vec<RpcCallContainer> = room.buffered_rpcs
+            .iter()
+            .filter(|call| call.object_id.is_none_or(|object_id| spawned.contains_key(&object_id)))
+            .cloned()
+            .collect();
+        if !calls.is_empty() {
+            log_debug!(Target::Rpc, "Replaying {} buffered calls to peer {}", calls.len(), peer_id);
+        }
+        for call in calls {
+            self.send_rpc(&BroadcastFilter::only(&[peer_id]), call);
+        }
+    }
+
+
+    /// Runs a peer's call here and relays it to the peers its target names. The sender has already
+    /// run its own copy when it was a target, so it never gets the call back.
+    pub fn route_rpc(&mut self, sender_id: i32, room_name: &str, mut call: RpcCallContainer) {
+        call.caller = sender_id;
+        let Some(room) = self.rooms.get_mut(room_name) else {
+            return;
+        };
+        let owner_id = call.object_id.and_then(|object_id| {
+            room.synced_players.lock().unwrap().get(&object_id).map(|wrapper| wrapper.owner_id)
+        });
+        if let Err(e) = authorize(&call, sender_id, owner_id) {
+            log_warn!(Target::Rpc, "Refused RPC from peer {}: {}", sender_id, e);
+            if let Some(response) = call.response(Err(e)) {
+                self.deliver_rpc_response(response);
+            }
+            return;
+        }
+        let single_peer = |peer_id: i32| {
+            (peer_id != HOST_ID && peer_id != sender_id).then(|| BroadcastFilter::only(&[peer_id]).in_room(room_name))
+        };
 
-        for target in target_vector.iter(){
-            if let Err(e) = self.send_message(&message, **target){
-                eprintln!("Failed to send message: {}", e);
+        let (run_here, relay) = match call.target {
+            RpcTarget::All => (true, Some(BroadcastFilter::excluding(&[sender_id]).in_room(room_name))),
+            RpcTarget::Others => (sender_id != HOST_ID, Some(BroadcastFilter::excluding(&[sender_id]).in_room(room_name))),
+            RpcTarget::Server => (true, None),
+            RpcTarget::Owner => {
+                let Some(owner_id) = owner_id else {
+                    log_warn!(Target::Rpc, "RPC {} targets the owner of an unknown object", call.function_name);
+                    if let Some(response) = call.response(Err(RpcError::UnknownObject(call.object_id))) {
+                        self.deliver_rpc_response(response);
+                    }
+                    return;
+                };
+                (owner_id == HOST_ID, single_peer(owner_id))
             }
+            RpcTarget::Peer(peer_id) => (peer_id == HOST_ID, single_peer(peer_id)),
+        };
+
+        if call.buffered && matches!(call.target, RpcTarget::All | RpcTarget::Others) {
+            let mut kept = call.clone();
+            // Late joiners run it as a plain call, nobody waits for their answer
+            kept.call_id = None;
+            room.buffered_rpcs.push(kept);
+        }
+        let room = &self.rooms[room_name];
+        let result = run_here.then(|| execute_rpc(&call, &room.player_map, &room.world)).flatten();
+        if let Some(response) = result.and_then(|result| call.response(result)) {
+            self.deliver_rpc_response(response);
+        }
+        if let Some(filter) = relay {
+            self.send_rpc(&filter, call);
         }
     }
 
 
+    /// Passes a call's response on to the peer that made the call, or completes it here for the host.
+    pub fn deliver_rpc_response(&mut self, response: RpcResponse) {
+        if response.caller == HOST_ID {
+            complete_call(response);
+            return;
+        }
+        let caller = response.caller;
+        let mut message: HashMap<String, ObjectType> = HashMap::new();
+        message.insert("goal".to_string(), ObjectType::StringMsg("rpc_response".to_string()));
+        message.insert("response".to_string(), ObjectType::RpcResult(response));
+        if let Err(e) = self.send_to_peer(&message, caller) {
+            log_error!(Target::Net, "Failed to send message: {}", e);
+        }
+    }
+
+
+    pub fn broadcast(&mut self, message: &HashMap<String, ObjectType>, filter: &BroadcastFilter) {
+        for peer_id in self.connections.targets(filter) {
+            if let Err(e) = self.send_to_peer(message, peer_id) {
+                log_error!(Target::Net, "Failed to send message: {}", e);
+            }
+        }
+    }
+
+
+    /// Players the peer should know about right now, marked as spawned for it.
+    fn relevant_players_for(&mut self, peer_id: Option<i32>) -> HashMap<i32, DataWrapper> {
+        self.refresh_synced_players();
+        let room_name = self.room_of_peer(peer_id);
+        let Some(room) = self.rooms.get(&room_name) else {
+            return HashMap::new();
+        };
+        let synced_players = room.synced_players.lock().unwrap().clone();
+        let Some(peer) = peer_id.and_then(|id| self.connections.get_mut(id)) else {
+            return synced_players;
+        };
+        let viewer = peer.owned_objects
+            .iter()
+            .find_map(|id| synced_players.get(id))
+            .map(|wrapper| vec2(wrapper.position_data.0, wrapper.position_data.1));
+
+        let now = Instant::now();
+        let mut relevant = HashMap::new();
+        for (object_id, wrapper) in synced_players.iter() {
+            let tier = self.relevancy.tier(viewer, vec2(wrapper.position_data.0, wrapper.position_data.1));
+            if tier == RelevancyTier::Culled {
+                continue;
+            }
+            let state = peer.replication.entry(*object_id).or_default();
+            state.spawned = true;
+            state.mark_sent(now);
+            relevant.insert(*object_id, *wrapper);
+        }
+        relevant
+    }
+
+
+    fn reject_connect(&self, client_address: SocketAddr, reason: RejectReason) -> HashMap<String, ObjectType> {
+        log_info!(Target::Net, "Rejected connection from {}: {}", client_address, reason);
+        let mut response_map = HashMap::new();
+        response_map.insert(String::from("goal"), ObjectType::StringMsg(String::from("reject_connect")));
+        response_map.insert(String::from("reason"), ObjectType::Rejection(reason));
+        response_map
+    }
+
+
     fn process_message(&mut self,message_received: &Message,client_address:SocketAddr) -> HashMap<String,ObjectType>{
         let mut response_map = HashMap::new();
         let received_map = message_received.get_message_map();
-        println!("{:?}",received_map);
+        log_trace!(Target::Net, "{:?}",received_map);
+
+        let sender_id = self.socket_to_id(client_address);
+        if let Some(peer) = sender_id.and_then(|id| self.connections.get_mut(id)) {
+            peer.mark_received(message_received.get_sequence());
+        }
+
         if received_map.contains_key("goal"){
             match received_map.get("goal"){
                 Some(ObjectType::StringMsg(goal)) => {
-                    println!("Received message with goal: {}", goal.as_str().bold());
+                    log_debug!(Target::Net, "Received message with goal: {}", goal);
+                    if goal != "sync" {
+                        match sender_id.and_then(|id| self.connections.get_mut(id)) {
+                            Some(peer) => {
+                                if peer.state == ConnectionState::Connecting {
+                                    peer.set_state(ConnectionState::Connected);
+                                }
+                            },
+                            None => {
+                                response_map.insert(String::from("goal"), ObjectType::StringMsg(String::from("reconnect_required")));
+                                return response_map;
+                            }
+                        }
+                    }
                     match goal.as_str() {
                         "sync" => {
+                            let spectator = matches!(received_map.get("spectator"), Some(ObjectType::Boolean(true)));
+                            let requested_room = match received_map.get("room") {
+                                Some(ObjectType::StringMsg(room)) => room.clone(),
+                                _ => String::new(),
+                            };
+                            if self.bans.is_banned(client_address.ip()) {
+                                return self.reject_connect(client_address, RejectReason::Banned);
+                            }
+
+                            // Only a session that actually resumes, or a peer already in the table,
+                            // skips admission. A stale token from before a restart or kick does not.
+                            let resumed_id = match (received_map.get("session_token"), received_map.get("migrate_from")) {
+                                (_, Some(ObjectType::Integer(previous_id))) => self.connections.resume_reserved(*previous_id, client_address),
+                                (Some(ObjectType::SessionToken(token)), _) => self.connections.resume(*token, client_address),
+                                _ => None,
+                            };
+                            let mut room_name = None;
+                            if resumed_id.is_none() && sender_id.is_none() {
+                                let rejection = match normalize_room_name(&requested_room) {
+                                    Some(name) => match self.admission_check(client_address, spectator, &name) {
+                                        Some(reason) => Some(reason),
+                                        None => {
+                                            room_name = self.room_for_join(&name);
+                                            room_name.is_none().then_some(RejectReason::RoomUnavailable)
+                                        }
+                                    },
+                                    None => Some(RejectReason::RoomUnavailable),
+                                };
+                                if let Some(reason) = rejection {
+                                    return self.reject_connect(client_address, reason);
+                                }
+                            }
+
+                            self.peer_list_dirty = true;
+                            let new_id = match resumed_id {
+                                Some(id) => {
+                                    log_info!(Target::Net, "Peer {} resumed its session from {}", id, client_address);
+                                    id
+                                },
+                                None => self.connections.register(client_address),
+                            };
+                            if let Some(peer) = self.connections.get_mut(new_id) {
+                                peer.is_spectator = spectator;
+                                if let Some(room_name) = room_name {
+                                    peer.room = room_name;
+                                }
+                            }
+                            if let Some(ObjectType::StringMsg(name)) = received_map.get("name") {
+                                self.set_display_name(new_id, name);
+                            }
+                            // The newcomer needs everyone's names even if its own did not change them
+                            self.names_dirty = true;
+                            let joined_room = self.room_of_peer(Some(new_id));
+                            if let Some(room) = self.rooms.get_mut(&joined_room) {
+                                room.lobby_dirty = true;
+                            }
                             response_map.insert(String::from("goal"), ObjectType::StringMsg(String::from("confirm connect")));
-                            let new_id = self.gen_new_id();
-                            self.user_map.insert(new_id,(client_address.ip(),CLIENT_PORT).into());
                             response_map.insert(String::from("id"), ObjectType::Integer(new_id));
+                            response_map.insert(String::from("room"), ObjectType::StringMsg(joined_room));
+                            response_map.insert(String::from("rpc_table"), ObjectType::RpcTable(RPC_IDS.names().to_vec()));
+                            if let Some(ObjectType::RpcTable(names)) = received_map.get("rpc_table") {
+                                RPC_IDS.check_against(&RpcIdTable::from_names(names.clone()), &format!("peer {}", new_id));
+                            }
+                            if let Some(peer) = self.connections.get(new_id) {
+                                response_map.insert(String::from("session_token"), ObjectType::SessionToken(peer.session_token));
+                            }
+                        },
+                        "pong" => {
+                            if let (Some(peer_id), Some(ObjectType::Integer(ping_id))) = (sender_id, received_map.get("ping_id")) {
+                                if let Some(peer) = self.connections.get_mut(peer_id) {
+                                    peer.complete_ping(*ping_id);
+                                }
+                            }
+                        },
+                        "lobby_update" => {
+                            let is_spectator = sender_id.and_then(|id| self.connections.get(id)).is_some_and(|peer| peer.is_spectator);
+                            match (sender_id, received_map.get("character"), received_map.get("ready")) {
+                                (Some(peer_id), Some(ObjectType::Character(character_type)), Some(ObjectType::Boolean(ready))) if !is_spectator => {
+                                    let room_name = self.room_of_peer(Some(peer_id));
+                                    self.update_lobby(&room_name, peer_id, LobbyEntry { character_type: *character_type, ready: *ready });
+                                },
+                                _ => log_warn!(Target::Net, "Ignoring lobby update from {}", client_address),
+                            }
+                        },
+                        "chat" => {
+                            let (Some(peer_id), Some(ObjectType::StringMsg(text))) = (sender_id, received_map.get("text")) else {
+                                log_warn!(Target::Net, "Invalid chat message from {}", client_address);
+                                return response_map;
+                            };
+                            let Some(text) = sanitize_chat(text) else {
+                                return response_map;
+                            };
+                            let allowed = self.connections
+                                .get_mut(peer_id)
+                                .is_some_and(|peer| peer.chat_limiter.try_send(Instant::now()));
+                            if allowed {
+                                let room_name = self.room_of_peer(Some(peer_id));
+                                self.relay_chat(&room_name, ChatMessage { sender_id: peer_id, text });
+                            } else {
+                                response_map.insert("goal".into(), ObjectType::StringMsg("chat_message".into()));
+                                response_map.insert("message".into(), ObjectType::Chat(ChatMessage::system("You are sending messages too fast")));
+                            }
                         },
                         "get_sync_players" => {
+                            let players = self.relevant_players_for(sender_id);
                             response_map.insert("goal".into(), ObjectType::StringMsg("ret_sync_players".into()));
-                            response_map.insert("players".into(), ObjectType::PlayerMap(self.synced_players.lock().unwrap().clone()));
+                            response_map.insert("players".into(), ObjectType::PlayerMap(players.clone()));
+                            // Buffered calls have to arrive after the players they act on
+                            if let Some(peer_id) = sender_id {
+                                if let Err(e) = self.send_to_peer(&response_map, peer_id) {
+                                    log_error!(Target::Net, "Failed to send message: {}", e);
+                                }
+                                response_map.clear();
+                                self.replay_buffered_rpcs(peer_id, &players);
+                            }
                         },
                         "add_player" => {
-                            if let Some(player_obj) = received_map.get("player") {
+                            if sender_id.and_then(|id| self.connections.get(id)).is_some_and(|peer| peer.is_spectator) {
+                                log_warn!(Target::Net, "Spectator at {} tried to add a player", client_address);
+                            } else if let Some(player_obj) = received_map.get("player") {
                                 match player_obj {
                                     ObjectType::Player(pl) => {
-                                        let mut world = self.world.lock().unwrap();
-                                        let mut pl = Player::construct_from_wrapper(*pl, &mut world, &*PLAYER_SIZE_DATA);
+                                        let Some(room) = self.rooms.get(&self.room_of_peer(sender_id)) else {
+                                            return response_map;
+                                        };
+                                        let (world_mutex, player_map_mutex) = (Arc::clone(&room.world), Arc::clone(&room.player_map));
+                                        let mut world = world_mutex.lock().unwrap();
+                                        let mut pl = Player::construct_from_wrapper(*pl, &mut world, &PLAYER_SIZE_DATA);
                                         drop(world);
                                         let new_id: i32;
-                                        if let Some(client_id) = self.socket_to_id(client_address){
-                                            if !self.synced_players.lock().unwrap().contains_key(&pl.get_object_id()){
+                                        if let Some(client_id) = sender_id {
+                                            if self.room_of_object(pl.get_object_id()).is_none() {
                                                 pl.set_owner(client_id);
                                                 new_id = self.add_player(pl, client_id);
-                                                let mut wrapper_map = self.player_map_mutex.lock().unwrap();
+                                                pl.set_object_id(new_id);
+                                                let mut wrapper_map = player_map_mutex.lock().unwrap();
                                                 wrapper_map.insert(new_id, pl);
-                                                
-                                                let mut target_vector: Vec<&SocketAddr> = Vec::new();
-                                                
-                                                for (_, target) in self.user_map.iter(){
-
-                                                    if target.to_string() != "127.0.0.1:13882" && *target != client_address {
-                                                        target_vector.push(target);
-                                                    }
-                                                }
-
-                                                let mut add_player_broadcast_message: HashMap<String, ObjectType> = HashMap::new();
-
-                                                add_player_broadcast_message.insert("goal".to_string(), ObjectType::StringMsg("add_player".to_string()));
-                                                add_player_broadcast_message.insert("player".to_string(), ObjectType::Player(pl.wrapper));
-
-                                                for target in target_vector.iter(){
-                                                    if let Err(e) = self.send_message(&add_player_broadcast_message, **target) {
-                                                        eprintln!("Failed to send message: {}", e); 
-                                                    }
-                                                }
-                                                
+                                                drop(wrapper_map);
+
                                                 response_map.insert("goal".into(), ObjectType::StringMsg("ret_player_obj_id".into()));
                                                 response_map.insert("id".into(), ObjectType::Integer(new_id));
                                             }
                                         }
-                                        
+
                                     },
                                     _ => {
-                                        eprintln!("player field is invalid type");
+                                        log_warn!(Target::Gameplay, "player field is invalid type");
                                     }
                                 }
                             } else {
-                                eprintln!("Missing 'player' field in received_map");
+                                log_warn!(Target::Gameplay, "Missing 'player' field in received_map");
                             }
                         },
-                        "object_pos_update" => {
-                            if let Some(ObjectType::Integer(pl_id)) = received_map.get("object_id") {
-                                if let Some(pl) = self.player_map_mutex.lock().unwrap().get_mut(pl_id) {
-                                    if let Some(ObjectType::MotionData(motion_data)) = received_map.get("motion_data"){
-                                        pl.wrapper.position_data = (motion_data.x, motion_data.y);
-                                        pl.wrapper.speed_data = (motion_data.x_speed, motion_data.y_speed);
-                                        pl.wrapper.state = motion_data.animation_state;
-                                        pl.wrapper.facing_right = motion_data.facing_right;
-
-                                        let mut locked_world = self.world.lock().unwrap();
-                                        locked_world.set_actor_position(pl.collider, vec2(motion_data.x, motion_data.y));
-                                        
-                                        pl.speed = vec2(motion_data.x_speed, motion_data.y_speed);
-                                        pl.facing_right = motion_data.facing_right;
-
-
-
-                                        let mut target_vector: Vec<&SocketAddr> = Vec::new();
-                            
-                                        for (_, target) in self.user_map.iter(){
-
-                                            if target.to_string() != "127.0.0.1:13882" && *target != client_address {
-                                                target_vector.push(target);
-                                            }
-                                        }
-                                        
-                                        self.send_motion_update(target_vector, *pl_id, motion_data.to_owned());
-
-                                    } else {
-                                        eprintln!("Motion Data for motion updated was not provided in the proper format");
-                                    }
-                                } else{
-                                    eprintln!("Object with id {} not found in server's player map", pl_id);
-                                }
-                            }else{
-                                eprintln!("object_id for motion_update_broadcast was incorrectly supplied");
+                        "player_input" => {
+                            let (Some(ObjectType::Integer(object_id)), Some(ObjectType::Input(input))) = (received_map.get("object_id"), received_map.get("input")) else {
+                                log_warn!(Target::Gameplay, "Malformed player input from {}", client_address);
+                                return response_map;
+                            };
+                            let owns_object = sender_id
+                                .and_then(|id| self.connections.get(id))
+                                .is_some_and(|peer| peer.owned_objects.contains(object_id));
+                            if owns_object {
+                                self.set_input(*object_id, input);
+                            } else {
+                                log_warn!(Target::Gameplay, "Peer at {} sent input for object {} it does not own", client_address, object_id);
                             }
                         },
                         "rpc_call" => {
-                            if let Some(ObjectType::RpcCall(rpc_data)) = received_map.get("rpc_data"){
-                                if let Some(func) = RPC_FN_TABLE.get(rpc_data.function_name.as_str()) {
-                                let mut runtime_args = Vec::new();
-                                let mut rpc_data_mut = rpc_data.clone();
-
-                                let mut player_map_locked = self.player_map_mutex.lock().unwrap();
-                                match func.get_runtime_params() {
-                                    RuntimeParams::Player => {
-                                        if let Some(ObjectType::Integer(player_id)) = rpc_data_mut.params.get(0) {
-                                            if let Some(player) = player_map_locked.get_mut(player_id) {
-                                                runtime_args.push(RuntimeArg::Player(player));
-                                            } else {
-                                                eprintln!("Player with ID {} not found", player_id);
-                                            }
-                                            rpc_data_mut.params.remove(0);
-                                        } else {
-                                            eprintln!("Expected player_id as first parameter for Player-based function");
-                                        }
-                                    }
-                                    RuntimeParams::None => { }
+                            let (Some(ObjectType::RpcCall(rpc_data)), Some(sender_id)) = (received_map.get("rpc_data"), sender_id) else {
+                                log_warn!(Target::Rpc, "Ignoring RPC from unknown peer at {}", client_address);
+                                return response_map;
+                            };
+                            let mut call = rpc_data.clone();
+                            if let Err(e) = RPC_IDS.decode(&mut call) {
+                                log_warn!(Target::Rpc, "Dropping RPC from {}: {}", client_address, e);
+                                call.caller = sender_id;
+                                if let Some(response) = call.response(Err(e)) {
+                                    self.deliver_rpc_response(response);
                                 }
-
-                                invoke_rpc(&rpc_data_mut, &mut runtime_args);
+                                return response_map;
                             }
-                    
-                    
-                                let mut target_vector: Vec<&SocketAddr> = Vec::new();
-                    
-                                for (_, target) in self.user_map.iter(){
-
-                                    if target.to_string() != "127.0.0.1:13882" && *target != client_address {
-                                        target_vector.push(target);
-                                    }
-                                }
+                            replay::record_rpc(&call);
+                            let room_name = self.room_of_peer(Some(sender_id));
+                            self.route_rpc(sender_id, &room_name, call);
+                        },
+                        "rpc_response" => {
+                            if let Some(ObjectType::RpcResult(response)) = received_map.get("response") {
+                                self.deliver_rpc_response(response.clone());
                             }
                         },
                         _ =>{
-                            println!("{}", "Unknown message type".red());
+                            log_warn!(Target::Net, "Unknown message type");
                         }
                     }
                 }
                 None =>{
-                    println!("Goal field empty");
+                    log_warn!(Target::Net, "Goal field empty");
                 }
                 _ =>{
-                    eprintln!("Invalid goal type!")
+                    log_warn!(Target::Net, "Invalid goal type!")
                 }
             }
         }
         response_map
     }
-    
-    
+
+
     fn receive_message(&mut self) -> Result<()> {
-        let mut buffer = [0u8; 1024];
-        
-        let (size, sender) = {
-            let socket = self.socket.lock().unwrap();
-            match socket.recv_from(&mut buffer) {
-                Ok(result) => result,
+        let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+
+        loop {
+            let (size, sender) = {
+                let socket = self.socket.lock().unwrap();
+                match socket.recv_from(&mut buffer) {
+                    Ok(result) => result,
+                    Err(e) => {
+                        if e.kind() != ErrorKind::WouldBlock {
+                            log_error!(Target::Net, "Error encountered while trying to receive message: {}", e);
+                        }
+                        return Ok(());
+                    }
+                }
+            };
+
+            capture::record(PacketDirection::Received, sender, &buffer[..size]);
+            match unpack_datagram(&buffer[..size]) {
+                Ok(messages) => {
+                    trace_messages(PacketDirection::Received, sender, &messages);
+                    for decoded in messages.iter() {
+                        let response_map = self.process_message(decoded, sender);
+
+                        if !response_map.is_empty() {
+                            match self.socket_to_id(sender) {
+                                Some(peer_id) => self.send_to_peer(&response_map, peer_id)?,
+                                None => self.send_message(&response_map, sender)?,
+                            }
+                            log_trace!(Target::Net, "Queued response: {:?}", response_map);
+                        }
+                    }
+                }
                 Err(e) => {
-                    if e.kind() != ErrorKind::WouldBlock {
-                        eprintln!("Error encountered while trying to receive message: {}", e);
+                    log_warn!(Target::Net, "Failed to decode message: {}", e);
+                }
+            }
+        }
+    }
+
+
+    fn maintain_connections(&mut self) {
+        let mut expired = Vec::new();
+        let mut ping_targets = Vec::new();
+
+        for (peer_id, peer) in self.connections.iter_mut() {
+            if peer.linger_expired() {
+                expired.push(*peer_id);
+                continue;
+            }
+            if peer.state != ConnectionState::Disconnecting && peer.is_timed_out() {
+                log_info!(Target::Net, "Peer {} at {} timed out", peer_id, peer.address);
+                peer.set_state(ConnectionState::Disconnecting);
+                self.peer_list_dirty = true;
+                continue;
+            }
+            if peer.state != ConnectionState::Disconnecting && peer.ping_due() {
+                ping_targets.push((*peer_id, peer.start_ping()));
+            }
+        }
+
+        for peer_id in expired {
+            if let Some(peer) = self.connections.detach(peer_id) {
+                log_info!(Target::Net, "Detached peer {} at {}, keeping its players for reconnection", peer_id, peer.address);
+                // A player that dropped out before the match should not hold up the ready check
+                if let Some(room) = self.rooms.get_mut(&peer.room) {
+                    if !room.lobby.started && room.lobby.leave(peer_id) {
+                        room.lobby_dirty = true;
                     }
-                    return Ok(());
                 }
             }
-        };
-    
-        match bincode::deserialize::<Message>(&buffer[..size]) {
-            Ok(decoded) => {
-                let response_map = self.process_message(&decoded, sender);
-                
-                if !response_map.is_empty() {
-                    self.send_message(&response_map, sender)?;
-                    println!("Sent response: {:?}", response_map);
+        }
+
+        for session in self.connections.expire_sessions() {
+            log_info!(Target::Net, "Session of peer {} expired", session.peer_id);
+            if self.display_names.remove(&session.peer_id).is_some() {
+                self.names_dirty = true;
+            }
+            if let Some(room) = self.rooms.get_mut(&session.room) {
+                if room.lobby.leave(session.peer_id) {
+                    room.lobby_dirty = true;
                 }
             }
-            Err(e) => {
-                println!("Failed to decode message: {}", e);
+            for object_id in session.owned_objects {
+                self.remove_player(object_id);
+            }
+        }
+
+        if self.peer_list_dirty {
+            self.peer_list_dirty = false;
+            let mut peer_list_message = HashMap::new();
+            peer_list_message.insert("goal".to_string(), ObjectType::StringMsg("peer_list".to_string()));
+            peer_list_message.insert("peers".to_string(), ObjectType::PeerList(self.connections.peer_infos()));
+            // Sent along so whoever takes over in a host migration keeps the same settings
+            peer_list_message.insert("config".to_string(), ObjectType::Config(self.config));
+            self.broadcast(&peer_list_message, &BroadcastFilter::all());
+        }
+
+        if self.names_dirty {
+            self.names_dirty = false;
+            let mut names_message = HashMap::new();
+            names_message.insert("goal".to_string(), ObjectType::StringMsg("names".to_string()));
+            names_message.insert("names".to_string(), ObjectType::Names(self.display_names.clone()));
+            self.broadcast(&names_message, &BroadcastFilter::all());
+        }
+
+        self.close_empty_rooms();
+
+        let mut lobby_messages = Vec::new();
+        for room in self.rooms.values_mut() {
+            if room.lobby_dirty || room.last_lobby_broadcast.elapsed() >= LOBBY_REFRESH_INTERVAL {
+                room.lobby_dirty = false;
+                room.last_lobby_broadcast = Instant::now();
+                let mut lobby_message = HashMap::new();
+                lobby_message.insert("goal".to_string(), ObjectType::StringMsg("lobby_state".to_string()));
+                lobby_message.insert("lobby".to_string(), ObjectType::Lobby(room.lobby.clone()));
+                lobby_messages.push((room.name.clone(), lobby_message));
+            }
+        }
+        for (room_name, lobby_message) in lobby_messages {
+            self.broadcast(&lobby_message, &BroadcastFilter::all().in_room(&room_name));
+        }
+
+        for (peer_id, ping_id) in ping_targets {
+            let mut ping_message = HashMap::new();
+            ping_message.insert("goal".to_string(), ObjectType::StringMsg("ping".to_string()));
+            ping_message.insert("ping_id".to_string(), ObjectType::Integer(ping_id));
+            if let Err(e) = self.send_to_peer(&ping_message, peer_id) {
+                log_warn!(Target::Net, "Failed to ping peer {}: {}", peer_id, e);
             }
         }
-        
+    }
+
+
+    /// Queues a message for the peer; queued messages are sent together by `flush_outgoing`.
+    pub fn send_to_peer(&mut self, message: &HashMap<String,ObjectType>, peer_id: i32) -> Result<()> {
+        let Some(peer) = self.connections.get_mut(peer_id) else {
+            log_warn!(Target::Net, "No peer with id {} in the connection table", peer_id);
+            return Ok(());
+        };
+
+        if let Ok(mut message_struct) = Message::new(peer_id, message.clone()) {
+            message_struct.set_sequence(peer.next_sequence());
+            peer.outgoing.push(message_struct);
+        }else{
+            log_error!(Target::Net, "Failed to create message: Message malformed");
+        }
+
         Ok(())
     }
 
 
+    pub fn flush_outgoing(&mut self) {
+        let socket = self.socket.lock().unwrap();
+        for (_, peer) in self.connections.iter_mut() {
+            if peer.outgoing.is_empty() {
+                continue;
+            }
+            trace_messages(PacketDirection::Sent, peer.address, &peer.outgoing);
+            let datagrams = pack_messages(std::mem::take(&mut peer.outgoing));
+            for datagram in datagrams.iter() {
+                capture::record(PacketDirection::Sent, peer.address, datagram);
+                if let Err(e) = socket.send_to(datagram, peer.address) {
+                    log_error!(Target::Net, "Failed to send batch to {}: {}", peer.address, e);
+                }
+            }
+        }
+    }
+
+
     pub fn send_message(&self,message: &HashMap<String,ObjectType>,target:SocketAddr) -> Result<()> {
         if let Ok(message_struct) = Message::new(-1, message.clone()) {
-            let message_bytes = bincode::serialize(&message_struct).unwrap();
-            
-            self.socket.lock().unwrap().send_to(&message_bytes, target)?;
-            println!("Sent packet to {}", target);
+            trace_messages(PacketDirection::Sent, target, std::slice::from_ref(&message_struct));
+            for datagram in pack_messages(vec![message_struct]) {
+                capture::record(PacketDirection::Sent, target, &datagram);
+                self.socket.lock().unwrap().send_to(&datagram, target)?;
+            }
         }else{
-            eprintln!("Failed to create message: Message malformed");
+            log_error!(Target::Net, "Failed to create message: Message malformed");
         }
-        
+
         Ok(())
     }
 
@@ -347,13 +1309,15 @@ impl Server {
                 {
                     let mut locked = mut_ref.lock().unwrap();
                     if let Err(e) = locked.receive_message() {
-                        eprintln!("Failed to receive message: {:?}", e);
+                        log_error!(Target::Net, "Failed to receive message: {:?}", e);
                     }
+                    locked.maintain_connections();
+                    locked.simulate();
+                    locked.replicate_objects();
+                    locked.flush_outgoing();
                 }
                 thread::sleep(Duration::from_millis(8));
             }
         });
-        self.user_map.insert(-1, SocketAddr::from_str(format!("127.0.0.1:{}",SERVER_PORT).as_str()).unwrap());
     }
 }
-
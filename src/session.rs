@@ -0,0 +1,60 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::logging::Target;
+
+pub const SESSION_GRACE_PERIOD: Duration = Duration::from_secs(30);
+const SESSION_TOKEN_FILE: &str = "session_tokens.json";
+
+/// A peer that dropped off but whose players are kept until the grace period runs out.
+#[derive(Debug, Clone)]
+pub struct DetachedSession {
+    pub peer_id: i32,
+    pub room: String,
+    pub owned_objects: Vec<i32>,
+    pub detached_at: Instant,
+}
+
+impl DetachedSession {
+    pub fn grace_expired(&self) -> bool {
+        self.detached_at.elapsed() >= SESSION_GRACE_PERIOD
+    }
+}
+
+
+pub fn generate_session_token(peer_id: i32) -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_i32(peer_id);
+    if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    hasher.finish()
+}
+
+
+fn load_token_file() -> HashMap<String, u64> {
+    std::fs::read_to_string(SESSION_TOKEN_FILE)
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+/// Looks up the token last handed out by the server at this address, so a restarted client can resume.
+pub fn load_session_token(server_address: SocketAddr) -> Option<u64> {
+    load_token_file().get(&server_address.to_string()).copied()
+}
+
+pub fn store_session_token(server_address: SocketAddr, token: u64) {
+    let mut tokens = load_token_file();
+    tokens.insert(server_address.to_string(), token);
+    match serde_json::to_string_pretty(&tokens) {
+        Ok(json) => {
+            if let Err(e) = std::fs::write(SESSION_TOKEN_FILE, json) {
+                log_warn!(Target::Net, "Failed to store session token: {}", e);
+            }
+        }
+        Err(e) => log_warn!(Target::Net, "Failed to serialize session tokens: {}", e),
+    }
+}
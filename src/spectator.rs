@@ -0,0 +1,90 @@
+use macroquad::prelude::*;
+use std::collections::HashMap;
+use crate::player::Player;
+
+const MAP_SIZE: Vec2 = vec2(320.0, 152.0);
+const PAN_SPEED: f32 = 160.0;
+const MIN_ZOOM: f32 = 1.0;
+const MAX_ZOOM: f32 = 4.0;
+
+/// Camera for spectator connections: pans freely, or follows one player at a time.
+pub struct SpectatorCamera {
+    target: Vec2,
+    zoom: f32,
+    following: Option<i32>,
+}
+
+impl Default for SpectatorCamera {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpectatorCamera {
+    pub fn new() -> Self {
+        SpectatorCamera {
+            target: MAP_SIZE / 2.0,
+            zoom: MIN_ZOOM,
+            following: None,
+        }
+    }
+
+    pub fn following(&self) -> Option<i32> {
+        self.following
+    }
+
+    /// Tab cycles through players in id order, Backspace returns to the free camera,
+    /// arrow keys pan and the mouse wheel zooms.
+    pub fn update(&mut self, players: &HashMap<i32, Player>, frame_time: f32) {
+        if is_key_pressed(KeyCode::Tab) {
+            let mut ids: Vec<i32> = players.keys().copied().collect();
+            ids.sort();
+            self.following = match self.following.and_then(|id| ids.iter().position(|other| *other == id)) {
+                Some(index) => ids.get(index + 1).copied(),
+                None => ids.first().copied(),
+            };
+        }
+        if is_key_pressed(KeyCode::Backspace) {
+            self.following = None;
+        }
+
+        let (_, wheel) = mouse_wheel();
+        if wheel != 0.0 {
+            self.zoom = (self.zoom * if wheel > 0.0 { 1.1 } else { 1.0 / 1.1 }).clamp(MIN_ZOOM, MAX_ZOOM);
+        }
+
+        match self.following.and_then(|id| players.get(&id)) {
+            Some(player) => {
+                self.target = vec2(player.wrapper.position_data.0 + 16.0, player.wrapper.position_data.1 + 32.0);
+            }
+            None => {
+                self.following = None;
+                let mut direction = Vec2::ZERO;
+                if is_key_down(KeyCode::Left) { direction.x -= 1.0; }
+                if is_key_down(KeyCode::Right) { direction.x += 1.0; }
+                if is_key_down(KeyCode::Up) { direction.y -= 1.0; }
+                if is_key_down(KeyCode::Down) { direction.y += 1.0; }
+                self.target += direction * PAN_SPEED / self.zoom * frame_time;
+            }
+        }
+        self.target = self.target.clamp(Vec2::ZERO, MAP_SIZE);
+    }
+
+    pub fn camera(&self) -> Camera2D {
+        let size = MAP_SIZE / self.zoom;
+        Camera2D::from_display_rect(Rect::new(
+            self.target.x - size.x / 2.0,
+            self.target.y + size.y / 2.0,
+            size.x,
+            -size.y,
+        ))
+    }
+
+    pub fn draw_hud(&self) {
+        let status = match self.following {
+            Some(id) => format!("Spectating - following player {} (Tab: next, Backspace: free camera)", id),
+            None => "Spectating - free camera (arrows: pan, wheel: zoom, Tab: follow a player)".to_string(),
+        };
+        draw_text(&status, 10.0, 20.0, 20.0, WHITE);
+    }
+}
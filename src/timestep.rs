@@ -0,0 +1,50 @@
+pub const DEFAULT_TICK_RATE: u32 = 60;
+pub const MIN_TICK_RATE: u32 = 10;
+pub const MAX_TICK_RATE: u32 = 240;
+/// Upper bound on ticks run in one frame, so a long stall does not snowball into ever longer frames.
+const MAX_TICKS_PER_FRAME: u32 = 8;
+
+/// Accumulator that turns variable frame times into a whole number of fixed simulation ticks.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedTimestep {
+    tick_rate: u32,
+    accumulator: f32,
+}
+
+impl FixedTimestep {
+    pub fn new(tick_rate: u32) -> Self {
+        FixedTimestep { tick_rate: tick_rate.clamp(MIN_TICK_RATE, MAX_TICK_RATE), accumulator: 0.0 }
+    }
+
+    pub fn tick_rate(&self) -> u32 {
+        self.tick_rate
+    }
+
+    /// Length of one tick in seconds, the delta every gameplay update receives.
+    pub fn dt(&self) -> f32 {
+        1.0 / self.tick_rate as f32
+    }
+
+    /// Adds the frame time and returns how many ticks to simulate this frame.
+    pub fn advance(&mut self, frame_time: f32) -> u32 {
+        let dt = self.dt();
+        self.accumulator += frame_time.max(0.0);
+        let ticks = ((self.accumulator / dt) as u32).min(MAX_TICKS_PER_FRAME);
+        self.accumulator -= ticks as f32 * dt;
+        // Drop time we refused to simulate instead of carrying it into the next frame
+        self.accumulator %= dt;
+        ticks
+    }
+
+    /// How far the clock is between the last simulated tick and the next one, from 0 to 1.
+    /// Rendering blends the previous and current tick by this factor.
+    pub fn alpha(&self) -> f32 {
+        (self.accumulator / self.dt()).clamp(0.0, 1.0)
+    }
+}
+
+impl Default for FixedTimestep {
+    fn default() -> Self {
+        FixedTimestep::new(DEFAULT_TICK_RATE)
+    }
+}
@@ -43,21 +43,23 @@ impl Spikes {
             width,
             height,
             time_to_live: ttl,
-            owner_object_id: owner_object_id,
-            damage: damage,
+            owner_object_id,
+            damage,
         }
     }
 
-    pub fn handle(&mut self, frame_time: f32, other_players: &mut Vec<&mut Player>) {
+    /// Returns the number of players killed by the spikes this frame.
+    pub fn handle(&mut self, frame_time: f32, other_players: &mut Vec<&mut Player>) -> i32 {
         self.time_to_live -= frame_time;
+        let mut kills = 0;
 
         for target in other_players.iter_mut(){
-            if target.wrapper.object_id != self.owner_object_id && !target.is_dead && Self::check_attack_collision(self, target){
-
-                target.take_damage(self.damage);
-            
+            if target.wrapper.object_id != self.owner_object_id && !target.is_dead && Self::check_attack_collision(self, target)
+                && target.take_damage(self.damage) {
+                kills += 1;
             }
         }        
+        kills
     }
 
     pub fn render(&self, src_rect: Rect, texture: &Texture2D, facing_right: bool) {